@@ -6,7 +6,7 @@ use serde_json::Value;
 use tracing::info;
 
 use new_york_calculate_core::{
-    buy_market, sell_market, Activate, Calculate, CalculateAgent, CalculateCommand,
+    buy_market, from_amount, sell_market, Activate, Calculate, CalculateAgent, CalculateCommand,
     CalculateResult, CandleTrait, Order, Symbol,
 };
 
@@ -63,22 +63,13 @@ impl Activate<Candle> for &CalculateIterActivate {
         *step += 1;
 
         let mut score = self.score.lock().unwrap();
-        *score = stats.balance
-            + stats
-                .assets_frozen
-                .iter()
-                .map(|r| prices.get(r.0).unwrap_or(&0f32) * r.1)
-                .sum::<f32>()
-            + stats
-                .assets_available
-                .iter()
-                .map(|r| prices.get(r.0).unwrap_or(&0f32) * r.1)
-                .sum::<f32>();
+        let balance = from_amount(stats.balance);
+        *score = from_amount(stats.get_equity(prices));
 
         info!(
             step = *step,
             score = *score,
-            balance = stats.balance,
+            balance,
             assets_frozen = ?stats.assets_frozen,
             assets_available =  ?stats.assets_available,
             "score"
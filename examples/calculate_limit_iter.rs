@@ -1,7 +1,7 @@
 use new_york_calculate_core::{
-    buy_market, cancel_limit, sell_limit, sell_market, Activate, Calculate, CalculateAgent,
-    CalculateCommand, CalculateResult, CandleTrait, Order, OrderSide, OrderStatus, OrderType,
-    Symbol, TimeStamp,
+    buy_market, cancel_limit, from_amount, sell_limit, sell_market, Activate, Calculate,
+    CalculateAgent, CalculateCommand, CalculateResult, CandleTrait, Order, OrderSide, OrderStatus,
+    OrderType, Symbol, TimeStamp,
 };
 use serde_json::Value;
 use std::collections::HashMap;
@@ -55,6 +55,7 @@ impl CandleTrait for Candle {
     }
 }
 
+#[allow(clippy::useless_conversion, clippy::unwrap_or_default)]
 impl Activate<Candle> for &CalculateIterActivate {
     fn activate(
         &self,
@@ -69,23 +70,14 @@ impl Activate<Candle> for &CalculateIterActivate {
 
         let mut actions = vec![];
         let mut data = self.data.lock().unwrap();
+        let balance = from_amount(stats.balance);
 
-        data.score = stats.balance
-            + stats
-                .assets_frozen
-                .iter()
-                .map(|r| prices.get(r.0).unwrap_or(&0f32) * r.1)
-                .sum::<f32>()
-            + stats
-                .assets_available
-                .iter()
-                .map(|r| prices.get(r.0).unwrap_or(&0f32) * r.1)
-                .sum::<f32>();
+        data.score = from_amount(stats.get_equity(prices));
 
         info!(
             step = candle.get_start_time(),
             score = data.score,
-            balance = stats.balance,
+            balance,
             assets_frozen = ?stats.assets_frozen,
             assets_available =  ?stats.assets_available,
             "score"
@@ -110,7 +102,7 @@ impl Activate<Candle> for &CalculateIterActivate {
 
         let price = prices.get(&candle.get_symbol()).unwrap_or(&0.0);
 
-        if candle.start_time % 1800 == 0 && price * 100f32 < stats.balance {
+        if candle.start_time % 1800 == 0 && price * 100f32 < balance {
             actions.push(buy_market!(candle.get_symbol(), 100.0));
             actions.push(sell_limit!(candle.get_symbol(), 100.0, price * 1.01));
         }
@@ -1,6 +1,6 @@
 use crate::order::Order;
 use crate::types::TimeStamp;
-use crate::{CalculateCommand, CalculateResult, CandleTrait, Symbol};
+use crate::{CalculateCommand, CalculateResult, CandleTrait, EquityPoint, Symbol};
 use std::collections::HashMap;
 
 pub trait Activate<C> {
@@ -16,5 +16,7 @@ pub trait Activate<C> {
 
     fn on_order(&mut self, _ts: TimeStamp, _order: &Order) {}
 
+    fn on_end_round(&mut self, _ts: TimeStamp, _equity: &EquityPoint) {}
+
     fn on_end(&mut self, _result: CalculateResult) {}
 }
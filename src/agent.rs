@@ -1,31 +1,85 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 
 use crate::activate::Activate;
 use crate::candle::CandleTrait;
-use crate::order::{Order, OrderSide, OrderStatus, OrderType};
+use crate::order::{
+    Order, OrderFilter, OrderReason, OrderSide, OrderStatus, OrderType, TimeInForce, Trailing,
+    TrailingDistance,
+};
 use crate::types::{OrderId, Symbol, TimeStamp, UserId};
 use crate::{
-    handle_buy_executed_order, handle_cancel_order, handle_sell_executed_order, CalculateCommand,
-    CalculateResult, CalculateStats,
+    from_amount, get_notional, handle_buy_executed_order, handle_cancel_order,
+    handle_release_asset, handle_repay_borrowed, handle_sell_executed_order, to_amount, Amount,
+    CalculateCommand, CalculateResult, CalculateStats, EquityPoint, Stake,
+};
+use bracket::Bracket;
+pub use commission::{
+    CommissionAsset, CommissionModel, CommissionTier, FlatCommission, Liquidity,
+    MakerTakerCommission, TieredCommission,
+};
+pub use errors::CalculateAgentError;
+pub use fill_model::{FillModel, FullFill, ParticipationFill};
+pub use fills::TouchRule;
+use fills::{
+    get_fill_liquidity, get_fill_price, get_next_touch, is_fillable, ratchet_trailing_stop,
+};
+pub use instrument_spec::InstrumentSpec;
+use ledger::Ledger;
+pub use ledger::{LotMatching, Trade};
+pub use margin::MarginConfig;
+pub use path::{
+    IntrabarPath, NearestExtremeFirst, OpenHighLowClose, OpenLowHighClose, Pessimistic,
 };
-use errors::CalculateAgentError;
-use tracing::{debug, instrument};
+pub use position::Position;
+pub use slippage::{FixedSlippage, NoSlippage, RangeSlippage, SlippageModel, VolumeSlippage};
+use tracing::{debug, instrument, warn};
 use uuid::Uuid;
 
+mod bracket;
+mod commission;
 mod errors;
+mod fill_model;
+mod fills;
+mod instrument_spec;
+mod ledger;
 mod macros;
+mod margin;
+mod path;
+mod position;
+mod slippage;
 
 pub struct CalculateAgent<T: Activate<C> + ?Sized, C: CandleTrait> {
-    balance: f32,
-    commission: f32,
-    min_balance: f32,
+    balance: Amount,
+    commission: Box<dyn CommissionModel>,
+    commission_asset: CommissionAsset,
+    fee_balance: Amount,
+    traded_notional: VecDeque<(TimeStamp, Amount)>,
+    min_equity: Amount,
+    peak_equity: Amount,
+    margin: Option<MarginConfig>,
+    intrabar_path: Box<dyn IntrabarPath>,
+    touch_rule: TouchRule,
+    slippage: Box<dyn SlippageModel<C>>,
+    fill_model: Box<dyn FillModel<C>>,
     portfolio_available: HashMap<Symbol, f32>,
     portfolio_frozen: HashMap<Symbol, f32>,
+    portfolio_borrowed: HashMap<Symbol, f32>,
+    positions: HashMap<Symbol, Position>,
+    ledger: Ledger,
+    prices: HashMap<Symbol, f32>,
+    instruments: HashMap<Symbol, InstrumentSpec>,
     activate: Box<T>,
     queue_orders: HashMap<Symbol, Vec<Order>>,
     executed_orders: Vec<Order>,
+    brackets: HashMap<OrderId, Bracket>,
+    /// Cash counted by both buy legs of a pair but held once, keyed by the stop-loss leg
+    shared_reserves: HashMap<OrderId, Amount>,
+    /// Quantity of the queued sells the holdings don't cover, borrowed once they execute
+    short_reserves: HashMap<OrderId, f32>,
+    /// Post-only orders waiting for the open of the first candle processing them
+    unposted: HashSet<OrderId>,
     candle: PhantomData<C>,
 }
 
@@ -35,19 +89,45 @@ where
     C: CandleTrait + Debug,
 {
     pub fn new(balance: f32, commission: f32, activate: Box<T>) -> CalculateAgent<T, C> {
+        let balance = to_amount(balance);
+
         CalculateAgent {
             balance,
             activate,
-            commission,
-            min_balance: balance,
+            commission: Box::new(FlatCommission { rate: commission }),
+            commission_asset: CommissionAsset::default(),
+            fee_balance: Amount::default(),
+            traded_notional: Default::default(),
+            min_equity: balance,
+            peak_equity: balance,
+            margin: None,
+            intrabar_path: Box::new(NearestExtremeFirst),
+            touch_rule: TouchRule::default(),
+            slippage: Box::new(NoSlippage),
+            fill_model: Box::new(FullFill),
             executed_orders: Default::default(),
             queue_orders: Default::default(),
+            brackets: Default::default(),
+            shared_reserves: Default::default(),
+            short_reserves: Default::default(),
+            unposted: Default::default(),
             portfolio_available: Default::default(),
             portfolio_frozen: Default::default(),
+            portfolio_borrowed: Default::default(),
+            positions: Default::default(),
+            ledger: Default::default(),
+            prices: Default::default(),
+            instruments: Default::default(),
             candle: PhantomData,
         }
     }
 
+    /// Enable margin mode, allowing sells beyond the available asset balance
+    pub fn with_margin(mut self, margin: MarginConfig) -> Self {
+        self.margin = Some(margin);
+        self
+    }
+
     /// Activate the agent
     #[instrument(level = "debug", skip(self))]
     pub fn activate(&self, candles: &[C], prices: &HashMap<Symbol, f32>) -> Vec<CalculateCommand> {
@@ -57,7 +137,7 @@ where
 
     /// Get the stats of the agent
     #[instrument(level = "debug", skip(self))]
-    pub fn get_stats(&self, candle: &C) -> CalculateStats {
+    pub fn get_stats(&self, candle: &C) -> CalculateStats<'_> {
         let count = self
             .portfolio_available
             .get(&candle.get_symbol())
@@ -76,46 +156,137 @@ where
             real: count * candle.get_open(),
             assets_available: &self.portfolio_available,
             assets_frozen: &self.portfolio_frozen,
+            assets_borrowed: &self.portfolio_borrowed,
+            positions: &self.positions,
         }
     }
 
+    /// Use the intrabar price path to decide which orders of a candle are touched first
+    pub fn with_intrabar_path(mut self, intrabar_path: Box<dyn IntrabarPath>) -> Self {
+        self.intrabar_path = intrabar_path;
+        self
+    }
+
+    /// Set whether touching the limit price is enough to fill a limit order
+    pub fn with_touch_rule(mut self, touch_rule: TouchRule) -> Self {
+        self.touch_rule = touch_rule;
+        self
+    }
+
+    /// Apply the slippage model to market fills
+    pub fn with_slippage(mut self, slippage: Box<dyn SlippageModel<C>>) -> Self {
+        self.slippage = slippage;
+        self
+    }
+
+    /// Limit the quantity of resting limit orders filled within a candle
+    pub fn with_fill_model(mut self, fill_model: Box<dyn FillModel<C>>) -> Self {
+        self.fill_model = fill_model;
+        self
+    }
+
+    /// Charge commissions according to the commission model instead of the flat rate
+    pub fn with_commission(mut self, commission: Box<dyn CommissionModel>) -> Self {
+        self.commission = commission;
+        self
+    }
+
+    /// Set the asset the commission is paid in
+    pub fn with_commission_asset(mut self, commission_asset: CommissionAsset) -> Self {
+        self.commission_asset = commission_asset;
+        self
+    }
+
+    /// Set the fee token balance used by [`CommissionAsset::Token`]
+    pub fn with_fee_balance(mut self, fee_balance: f32) -> Self {
+        self.fee_balance = to_amount(fee_balance);
+        self
+    }
+
+    /// Set how exits are paired with entries in the trade ledger
+    pub fn with_lot_matching(mut self, matching: LotMatching) -> Self {
+        self.ledger = Ledger::new(matching);
+        self
+    }
+
+    /// Validate and round the orders of the symbol against its trading rules
+    pub fn with_instrument(mut self, symbol: Symbol, spec: InstrumentSpec) -> Self {
+        self.instruments.insert(symbol, spec);
+        self
+    }
+
     /// Buy an order
+    #[allow(clippy::too_many_arguments)]
     #[instrument(level = "debug", skip(self))]
     pub fn buy_order(
         &mut self,
         candle: &C,
         price: f32,
+        stop_price: Option<f32>,
         qty: f32,
         order_type: OrderType,
         expiration: Option<TimeStamp>,
+        time_in_force: TimeInForce,
         id: Option<OrderId>,
         user_id: Option<UserId>,
     ) -> Result<Order, CalculateAgentError> {
-        let order_sum = qty * price;
+        let slippage = match order_type {
+            OrderType::Market => self
+                .slippage
+                .get_slippage(&OrderSide::Buy, price, qty, candle),
+            _ => 0.0,
+        };
 
-        if self.balance < order_sum {
-            return Err(CalculateAgentError::InsufficientBalance {
-                available: self.balance,
-                required: order_sum,
-            });
-        }
+        let price = price + slippage;
 
-        let order = Order {
+        let mut order = Order {
             created_at: candle.get_start_time(),
             finished_at: 0,
             price,
+            stop_price,
             qty,
+            filled_qty: 0.0,
+            last_qty: 0.0,
             symbol: candle.get_symbol(),
             id: id.unwrap_or(Uuid::new_v4()),
-            commission: order_sum * self.commission,
+            commission: Amount::default(),
+            slippage,
             status: OrderStatus::Open,
+            reason: None,
             side: OrderSide::Buy,
             order_type,
             expiration,
+            time_in_force,
             user_id,
+            parent_id: None,
+            oco_id: None,
+            trailing: None,
         };
 
-        self.balance -= order_sum;
+        if let Err(error) = self.apply_instrument(&mut order) {
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        if let Err(error) = self.check_bracket(&order) {
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        order.commission = self.get_commission(order_type, get_notional(order.price, order.qty));
+
+        // a market order always takes liquidity
+        if order_type == OrderType::Market {
+            if let Err(error) = check_post_only(&order, order.price) {
+                return Err(self.reject_order(order, candle, error));
+            }
+        }
+
+        if let Err(error) = self.check_fee_balance(order.commission) {
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        if let Err(error) = self.reserve_balance(get_notional(order.price, order.qty)) {
+            return Err(self.reject_order(order, candle, error));
+        }
 
         self.activate.on_order(candle.get_start_time(), &order);
 
@@ -124,7 +295,14 @@ where
                 let executed_order = handle_buy_executed_order!(self, order, candle);
                 self.executed_orders.push(executed_order);
             }
-            OrderType::Limit => {
+            OrderType::Limit
+            | OrderType::StopMarket
+            | OrderType::StopLimit
+            | OrderType::TrailingStop => {
+                if order.time_in_force == TimeInForce::PostOnly {
+                    self.unposted.insert(order.id);
+                }
+
                 self.queue_orders
                     .entry(order.symbol.clone())
                     .or_default()
@@ -136,57 +314,78 @@ where
     }
 
     /// Sell an order
+    #[allow(clippy::too_many_arguments)]
     #[instrument(level = "debug", skip(self))]
     pub fn sell_order(
         &mut self,
         candle: &C,
         price: f32,
+        stop_price: Option<f32>,
         qty: f32,
         order_type: OrderType,
         expiration: Option<TimeStamp>,
+        time_in_force: TimeInForce,
         id: Option<OrderId>,
         user_id: Option<UserId>,
     ) -> Result<Order, CalculateAgentError> {
-        let portfolio_amount = self
-            .portfolio_available
-            .get(&candle.get_symbol())
-            .unwrap_or(&0.0);
-
-        if qty > *portfolio_amount {
-            return Err(CalculateAgentError::InsufficientAssetBalance {
-                symbol: candle.get_symbol(),
-                available: *portfolio_amount,
-                required: qty,
-            });
-        }
-
-        self.portfolio_available
-            .entry(candle.get_symbol())
-            .and_modify(|v| *v -= qty)
-            .or_insert(0.0);
-
-        self.portfolio_frozen
-            .entry(candle.get_symbol())
-            .and_modify(|v| *v += qty)
-            .or_insert(qty);
+        let slippage = match order_type {
+            OrderType::Market => self
+                .slippage
+                .get_slippage(&OrderSide::Sell, price, qty, candle),
+            _ => 0.0,
+        };
 
-        let order_sum = qty * price;
+        let price = price - slippage;
 
-        let order = Order {
+        let mut order = Order {
             id: id.unwrap_or(Uuid::new_v4()),
             created_at: candle.get_start_time(),
             finished_at: 0,
             symbol: candle.get_symbol(),
             price,
+            stop_price,
             qty,
-            commission: order_sum * self.commission,
+            filled_qty: 0.0,
+            last_qty: 0.0,
+            commission: Amount::default(),
+            slippage,
             status: OrderStatus::Open,
+            reason: None,
             side: OrderSide::Sell,
             order_type,
             expiration,
+            time_in_force,
             user_id,
+            parent_id: None,
+            oco_id: None,
+            trailing: None,
         };
 
+        if let Err(error) = self.apply_instrument(&mut order) {
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        if let Err(error) = self.check_bracket(&order) {
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        order.commission = self.get_commission(order_type, get_notional(order.price, order.qty));
+
+        // a market order always takes liquidity
+        if order_type == OrderType::Market {
+            if let Err(error) = check_post_only(&order, order.price) {
+                return Err(self.reject_order(order, candle, error));
+            }
+        }
+
+        if let Err(error) = self.check_fee_balance(order.commission) {
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        if let Err(error) = self.freeze_asset(order.id, candle, order.price, order.qty) {
+            return Err(self.reject_order(order, candle, error));
+        }
+
         self.activate.on_order(candle.get_start_time(), &order);
 
         match order_type {
@@ -194,7 +393,14 @@ where
                 let executed_order = handle_sell_executed_order!(self, order, candle);
                 self.executed_orders.push(executed_order);
             }
-            OrderType::Limit => {
+            OrderType::Limit
+            | OrderType::StopMarket
+            | OrderType::StopLimit
+            | OrderType::TrailingStop => {
+                if order.time_in_force == TimeInForce::PostOnly {
+                    self.unposted.insert(order.id);
+                }
+
                 self.queue_orders
                     .entry(order.symbol.clone())
                     .or_default()
@@ -205,6 +411,358 @@ where
         Ok(order)
     }
 
+    /// Fit the order to the trading rules of its symbol
+    fn apply_instrument(&self, order: &mut Order) -> Result<(), CalculateAgentError> {
+        let Some(spec) = self.instruments.get(&order.symbol) else {
+            return Ok(());
+        };
+
+        if order.order_type != OrderType::Market {
+            order.price = spec.get_price(&order.symbol, order.price)?;
+            order.stop_price = order
+                .stop_price
+                .map(|stop_price| spec.get_price(&order.symbol, stop_price))
+                .transpose()?;
+        }
+
+        order.qty = spec.get_qty(&order.symbol, order.qty)?;
+
+        spec.check(&order.symbol, order.price, order.qty)
+    }
+
+    /// Refuse a bracket entry whose exits are off the tick grid or on the wrong side of the entry
+    fn check_bracket(&self, entry: &Order) -> Result<(), CalculateAgentError> {
+        let Some(bracket) = self.brackets.get(&entry.id) else {
+            return Ok(());
+        };
+
+        if let Some(spec) = self.instruments.get(&entry.symbol) {
+            spec.get_price(&entry.symbol, bracket.take_profit)?;
+            spec.get_price(&entry.symbol, bracket.stop_loss)?;
+        }
+
+        let valid = match entry.side {
+            OrderSide::Buy => bracket.stop_loss < entry.price && entry.price < bracket.take_profit,
+            OrderSide::Sell => bracket.take_profit < entry.price && entry.price < bracket.stop_loss,
+        };
+
+        if !valid {
+            return Err(CalculateAgentError::InvalidBracketPrices {
+                price: entry.price,
+                take_profit: bracket.take_profit,
+                stop_loss: bracket.stop_loss,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Report an order the agent refused to place
+    fn reject_order(
+        &mut self,
+        mut order: Order,
+        candle: &C,
+        error: CalculateAgentError,
+    ) -> CalculateAgentError {
+        order.status = OrderStatus::Rejected;
+        order.reason = Some(OrderReason::Rejected(error.clone()));
+        order.finished_at = candle.get_start_time();
+
+        debug!(order = ?order, "order rejected");
+
+        self.activate.on_order(candle.get_start_time(), &order);
+
+        error
+    }
+
+    /// Base quantity of the stake at the price the order is expected to fill,
+    /// market orders include the slippage
+    fn get_stake_qty(
+        &self,
+        stake: Stake,
+        side: &OrderSide,
+        order_type: OrderType,
+        price: f32,
+        candle: &C,
+    ) -> f32 {
+        let notional = match stake {
+            Stake::Base(qty) => return qty,
+            Stake::Quote(notional) => notional,
+            Stake::Equity(fraction) => from_amount(self.get_equity()) * fraction,
+        };
+
+        let price = match (order_type, side) {
+            (OrderType::Market, OrderSide::Buy) => {
+                price
+                    + self
+                        .slippage
+                        .get_slippage(side, price, notional / price, candle)
+            }
+            (OrderType::Market, OrderSide::Sell) => {
+                price
+                    - self
+                        .slippage
+                        .get_slippage(side, price, notional / price, candle)
+            }
+            _ => price,
+        };
+
+        notional / price
+    }
+
+    /// Commission of an order according to the commission model and the traded notional
+    fn get_commission(&self, order_type: OrderType, notional: Amount) -> Amount {
+        let traded = self
+            .traded_notional
+            .iter()
+            .map(|(_, notional)| notional)
+            .sum();
+
+        // the estimate at placement expects a limit order to rest on the book
+        let liquidity = match order_type {
+            OrderType::Limit => Liquidity::Maker,
+            _ => Liquidity::Taker,
+        };
+
+        self.commission.get_commission(liquidity, notional, traded)
+    }
+
+    /// Drop the traded notional that left the commission window
+    fn prune_traded_notional(&mut self, ts: TimeStamp) {
+        let Some(window) = self.commission.get_window() else {
+            self.traded_notional.clear();
+            return;
+        };
+
+        while let Some((traded_at, _)) = self.traded_notional.front() {
+            if traded_at + window >= ts {
+                break;
+            }
+
+            self.traded_notional.pop_front();
+        }
+    }
+
+    /// Refuse an order whose commission the fee token balance cannot cover
+    fn check_fee_balance(&self, commission: Amount) -> Result<(), CalculateAgentError> {
+        if self.commission_asset == CommissionAsset::Token && self.fee_balance < commission {
+            return Err(CalculateAgentError::InsufficientFeeBalance {
+                available: self.fee_balance,
+                required: commission,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Deduct the order sum from the balance
+    fn reserve_balance(&mut self, amount: Amount) -> Result<(), CalculateAgentError> {
+        if self.balance < amount {
+            return Err(CalculateAgentError::InsufficientBalance {
+                available: self.balance,
+                required: amount,
+            });
+        }
+
+        self.balance -= amount;
+
+        Ok(())
+    }
+
+    /// Move the held asset quantity from available to frozen, the missing part is
+    /// reserved against the margin and only borrowed when the sell executes
+    fn freeze_asset(
+        &mut self,
+        id: OrderId,
+        candle: &C,
+        price: f32,
+        qty: f32,
+    ) -> Result<(), CalculateAgentError> {
+        let portfolio_amount = *self
+            .portfolio_available
+            .get(&candle.get_symbol())
+            .unwrap_or(&0.0);
+
+        let held = qty.min(portfolio_amount.max(0.0));
+        let short = qty - held;
+
+        if short > 0.0 {
+            self.check_margin(candle, price, short)?;
+
+            self.short_reserves
+                .entry(id)
+                .and_modify(|v| *v += short)
+                .or_insert(short);
+        }
+
+        self.portfolio_available
+            .entry(candle.get_symbol())
+            .and_modify(|v| *v -= held)
+            .or_insert(0.0);
+
+        self.portfolio_frozen
+            .entry(candle.get_symbol())
+            .and_modify(|v| *v += held)
+            .or_insert(held);
+
+        Ok(())
+    }
+
+    /// Place the take-profit and stop-loss legs of an executed bracket entry
+    #[instrument(level = "debug", skip(self))]
+    fn place_bracket_legs(&mut self, entry: &Order, candle: &C) -> Result<(), CalculateAgentError> {
+        let Some(bracket) = self.brackets.remove(&entry.id) else {
+            return Ok(());
+        };
+
+        let side = match entry.side {
+            OrderSide::Buy => OrderSide::Sell,
+            OrderSide::Sell => OrderSide::Buy,
+        };
+
+        let take_profit_id = Uuid::new_v4();
+        let stop_loss_id = Uuid::new_v4();
+
+        // the legs close what the entry actually credited
+        let qty = bracket.filled_qty;
+
+        let mut take_profit = Order {
+            id: take_profit_id,
+            created_at: candle.get_start_time(),
+            finished_at: 0,
+            symbol: entry.symbol.clone(),
+            price: bracket.take_profit,
+            stop_price: None,
+            qty,
+            filled_qty: 0.0,
+            last_qty: 0.0,
+            commission: Amount::default(),
+            slippage: 0.0,
+            status: OrderStatus::Open,
+            reason: None,
+            side: side.clone(),
+            order_type: OrderType::Limit,
+            expiration: None,
+            time_in_force: TimeInForce::GoodTillCancel,
+            user_id: entry.user_id.clone(),
+            parent_id: Some(entry.id),
+            oco_id: Some(stop_loss_id),
+            trailing: None,
+        };
+
+        let mut stop_loss = Order {
+            id: stop_loss_id,
+            price: bracket.stop_loss,
+            stop_price: Some(bracket.stop_loss),
+            order_type: OrderType::StopMarket,
+            oco_id: Some(take_profit_id),
+            ..take_profit.clone()
+        };
+
+        let fitted = self
+            .apply_instrument(&mut take_profit)
+            .and_then(|_| self.apply_instrument(&mut stop_loss));
+
+        if let Err(error) = fitted {
+            self.reject_order(take_profit, candle, error.clone());
+            return Err(self.reject_order(stop_loss, candle, error));
+        }
+
+        for order in [&mut take_profit, &mut stop_loss] {
+            order.commission =
+                self.get_commission(order.order_type, get_notional(order.price, order.qty));
+        }
+
+        let reserved = match side {
+            // only one buy leg can fill, the pair holds the larger notional once
+            OrderSide::Buy => {
+                let take_profit_notional = get_notional(take_profit.price, take_profit.qty);
+                let stop_loss_notional = get_notional(stop_loss.price, stop_loss.qty);
+                let shared = take_profit_notional.min(stop_loss_notional);
+
+                self.reserve_balance(take_profit_notional + stop_loss_notional - shared)
+                    .map(|_| {
+                        self.shared_reserves.insert(stop_loss_id, shared);
+                    })
+            }
+            // sell legs share the frozen quantity, it is released once for the pair
+            OrderSide::Sell => {
+                self.freeze_asset(stop_loss_id, candle, take_profit.price, take_profit.qty)
+            }
+        };
+
+        if let Err(error) = reserved {
+            self.reject_order(take_profit, candle, error.clone());
+            return Err(self.reject_order(stop_loss, candle, error));
+        }
+
+        for order in [take_profit, stop_loss] {
+            self.activate.on_order(candle.get_start_time(), &order);
+
+            self.queue_orders
+                .entry(order.symbol.clone())
+                .or_default()
+                .push(order);
+        }
+
+        Ok(())
+    }
+
+    /// Settle links of an order that left the queue
+    #[instrument(level = "debug", skip(self))]
+    fn on_order_finished(&mut self, order: &Order, candle: &C) {
+        self.unposted.remove(&order.id);
+
+        if let Some(oco_id) = order.oco_id {
+            self.cancel_linked_order(&order.symbol, oco_id, candle);
+        }
+
+        if order.filled_qty > 0.0 {
+            if let Err(e) = self.place_bracket_legs(order, candle) {
+                warn!(error = ?e, order = ?order, "Error placing bracket legs");
+            }
+        } else {
+            self.brackets.remove(&order.id);
+        }
+    }
+
+    /// Cancel the remaining leg of a one-cancels-the-other pair
+    #[instrument(level = "debug", skip(self))]
+    fn cancel_linked_order(&mut self, symbol: &Symbol, id: OrderId, candle: &C) {
+        let Some(orders) = self.queue_orders.get_mut(symbol) else {
+            return;
+        };
+
+        let Some(position) = orders.iter().position(|o| o.id == id) else {
+            return;
+        };
+
+        let mut order = orders.remove(position);
+
+        if order.side == OrderSide::Buy {
+            // the part held for both legs stays with the leg that remains
+            let shared = self
+                .shared_reserves
+                .remove(&order.id)
+                .or_else(|| {
+                    order
+                        .oco_id
+                        .and_then(|oco_id| self.shared_reserves.remove(&oco_id))
+                })
+                .unwrap_or_default();
+
+            self.balance += get_notional(order.price, order.qty - order.filled_qty) - shared;
+        }
+
+        order.last_qty = 0.0;
+        order.status = OrderStatus::Cancel;
+        order.finished_at = candle.get_start_time();
+
+        self.activate.on_order(candle.get_start_time(), &order);
+
+        self.executed_orders.push(order);
+    }
+
     /// Perform an order
     #[instrument(level = "debug", skip(self))]
     pub fn perform_order(
@@ -212,14 +770,24 @@ where
         command: CalculateCommand,
         candle: &C,
     ) -> Result<Option<Order>, CalculateAgentError> {
+        self.prune_traded_notional(candle.get_start_time());
+
         match command {
             CalculateCommand::BuyMarket { stake, user_id, .. } => self
                 .buy_order(
                     candle,
                     candle.get_open(),
-                    stake,
+                    None,
+                    self.get_stake_qty(
+                        stake,
+                        &OrderSide::Buy,
+                        OrderType::Market,
+                        candle.get_open(),
+                        candle,
+                    ),
                     OrderType::Market,
                     None,
+                    TimeInForce::GoodTillCancel,
                     Some(Uuid::new_v4()),
                     user_id,
                 )
@@ -228,9 +796,17 @@ where
                 .sell_order(
                     candle,
                     candle.get_open(),
-                    stake,
+                    None,
+                    self.get_stake_qty(
+                        stake,
+                        &OrderSide::Sell,
+                        OrderType::Market,
+                        candle.get_open(),
+                        candle,
+                    ),
                     OrderType::Market,
                     None,
+                    TimeInForce::GoodTillCancel,
                     Some(Uuid::new_v4()),
                     user_id,
                 )
@@ -239,15 +815,18 @@ where
                 stake,
                 price,
                 expiration,
+                time_in_force,
                 user_id,
                 ..
             } => self
                 .buy_order(
                     candle,
                     price,
-                    stake,
+                    None,
+                    self.get_stake_qty(stake, &OrderSide::Buy, OrderType::Limit, price, candle),
                     OrderType::Limit,
                     expiration,
+                    time_in_force,
                     Some(Uuid::new_v4()),
                     user_id,
                 )
@@ -256,436 +835,3719 @@ where
                 stake,
                 price,
                 expiration,
+                time_in_force,
                 user_id,
                 ..
             } => self
                 .sell_order(
                     candle,
                     price,
-                    stake,
+                    None,
+                    self.get_stake_qty(stake, &OrderSide::Sell, OrderType::Limit, price, candle),
                     OrderType::Limit,
                     expiration,
+                    time_in_force,
                     Some(Uuid::new_v4()),
                     user_id,
                 )
                 .map(Some),
-            CalculateCommand::None | CalculateCommand::Unknown => Ok(None),
-            CalculateCommand::CancelLimit { symbol, id } => {
-                self.cancel_order(symbol, id, candle);
-                Ok(None)
-            }
-        }
-    }
-
-    /// Perform a candle
-    #[instrument(level = "debug", skip(self))]
-    pub fn perform_candle(&mut self, candle: &C) {
-        if let Some(orders) = self.queue_orders.get_mut(&candle.get_symbol()) {
-            let mut executed_ids = HashSet::new();
-
-            for order in orders.iter_mut() {
-                let mut executed_order = match order.side {
-                    OrderSide::Buy => {
-                        if order.price > candle.get_low() {
-                            Some(handle_buy_executed_order!(self, order, candle))
-                        } else {
-                            None
-                        }
-                    }
-                    OrderSide::Sell => {
-                        if order.price < candle.get_high() {
-                            Some(handle_sell_executed_order!(self, order, candle))
-                        } else {
-                            None
-                        }
-                    }
-                };
-
-                if executed_order.is_none() {
-                    if let Some(expiration) = order.expiration {
-                        if order.created_at + expiration < candle.get_start_time() {
-                            executed_order = Some(handle_cancel_order!(self, order, candle));
-                        }
-                    }
-                }
-
-                if let Some(executed_order) = executed_order {
-                    executed_ids.insert(executed_order.id);
-                    self.executed_orders.push(executed_order);
-                }
-            }
-
-            orders.retain(|o| !executed_ids.contains(&o.id));
-        }
-
-        debug!(
-            symbol = candle.get_symbol(),
-            portfolio_available = ?self.portfolio_available.get(&candle.get_symbol()),
-            portfolio_frozen = ?self.portfolio_frozen.get(&candle.get_symbol()),
-            "perform_candle done"
-        );
-    }
-
-    /// Perform a cancel order
-    #[instrument(level = "debug", skip(self))]
-    fn cancel_order(&mut self, symbol: Symbol, id: OrderId, candle: &C) {
-        let Some(orders) = self.queue_orders.get_mut(&symbol) else {
-            debug!(symbol = symbol, "cancel order symbol not found");
-            return;
+            CalculateCommand::BuyStop {
+                stake,
+                stop_price,
+                expiration,
+                user_id,
+                ..
+            } => self
+                .buy_order(
+                    candle,
+                    stop_price,
+                    Some(stop_price),
+                    self.get_stake_qty(
+                        stake,
+                        &OrderSide::Buy,
+                        OrderType::StopMarket,
+                        stop_price,
+                        candle,
+                    ),
+                    OrderType::StopMarket,
+                    expiration,
+                    TimeInForce::GoodTillCancel,
+                    Some(Uuid::new_v4()),
+                    user_id,
+                )
+                .map(Some),
+            CalculateCommand::SellStop {
+                stake,
+                stop_price,
+                expiration,
+                user_id,
+                ..
+            } => self
+                .sell_order(
+                    candle,
+                    stop_price,
+                    Some(stop_price),
+                    self.get_stake_qty(
+                        stake,
+                        &OrderSide::Sell,
+                        OrderType::StopMarket,
+                        stop_price,
+                        candle,
+                    ),
+                    OrderType::StopMarket,
+                    expiration,
+                    TimeInForce::GoodTillCancel,
+                    Some(Uuid::new_v4()),
+                    user_id,
+                )
+                .map(Some),
+            CalculateCommand::BuyStopLimit {
+                stake,
+                stop_price,
+                price,
+                expiration,
+                user_id,
+                ..
+            } => self
+                .buy_order(
+                    candle,
+                    price,
+                    Some(stop_price),
+                    self.get_stake_qty(stake, &OrderSide::Buy, OrderType::StopLimit, price, candle),
+                    OrderType::StopLimit,
+                    expiration,
+                    TimeInForce::GoodTillCancel,
+                    Some(Uuid::new_v4()),
+                    user_id,
+                )
+                .map(Some),
+            CalculateCommand::SellStopLimit {
+                stake,
+                stop_price,
+                price,
+                expiration,
+                user_id,
+                ..
+            } => self
+                .sell_order(
+                    candle,
+                    price,
+                    Some(stop_price),
+                    self.get_stake_qty(
+                        stake,
+                        &OrderSide::Sell,
+                        OrderType::StopLimit,
+                        price,
+                        candle,
+                    ),
+                    OrderType::StopLimit,
+                    expiration,
+                    TimeInForce::GoodTillCancel,
+                    Some(Uuid::new_v4()),
+                    user_id,
+                )
+                .map(Some),
+            CalculateCommand::BuyBracket {
+                stake,
+                price,
+                take_profit,
+                stop_loss,
+                expiration,
+                user_id,
+                ..
+            } => {
+                let id = Uuid::new_v4();
+
+                self.brackets.insert(
+                    id,
+                    Bracket {
+                        take_profit,
+                        stop_loss,
+                        filled_qty: 0.0,
+                    },
+                );
+
+                let order = match price {
+                    Some(price) => self.buy_order(
+                        candle,
+                        price,
+                        None,
+                        self.get_stake_qty(stake, &OrderSide::Buy, OrderType::Limit, price, candle),
+                        OrderType::Limit,
+                        expiration,
+                        TimeInForce::GoodTillCancel,
+                        Some(id),
+                        user_id,
+                    ),
+                    None => self.buy_order(
+                        candle,
+                        candle.get_open(),
+                        None,
+                        self.get_stake_qty(
+                            stake,
+                            &OrderSide::Buy,
+                            OrderType::Market,
+                            candle.get_open(),
+                            candle,
+                        ),
+                        OrderType::Market,
+                        None,
+                        TimeInForce::GoodTillCancel,
+                        Some(id),
+                        user_id,
+                    ),
+                };
+
+                self.perform_bracket(id, order, candle).map(Some)
+            }
+            CalculateCommand::SellBracket {
+                stake,
+                price,
+                take_profit,
+                stop_loss,
+                expiration,
+                user_id,
+                ..
+            } => {
+                let id = Uuid::new_v4();
+
+                self.brackets.insert(
+                    id,
+                    Bracket {
+                        take_profit,
+                        stop_loss,
+                        filled_qty: 0.0,
+                    },
+                );
+
+                let order = match price {
+                    Some(price) => self.sell_order(
+                        candle,
+                        price,
+                        None,
+                        self.get_stake_qty(
+                            stake,
+                            &OrderSide::Sell,
+                            OrderType::Limit,
+                            price,
+                            candle,
+                        ),
+                        OrderType::Limit,
+                        expiration,
+                        TimeInForce::GoodTillCancel,
+                        Some(id),
+                        user_id,
+                    ),
+                    None => self.sell_order(
+                        candle,
+                        candle.get_open(),
+                        None,
+                        self.get_stake_qty(
+                            stake,
+                            &OrderSide::Sell,
+                            OrderType::Market,
+                            candle.get_open(),
+                            candle,
+                        ),
+                        OrderType::Market,
+                        None,
+                        TimeInForce::GoodTillCancel,
+                        Some(id),
+                        user_id,
+                    ),
+                };
+
+                self.perform_bracket(id, order, candle).map(Some)
+            }
+            CalculateCommand::BuyTrailingStop {
+                stake,
+                distance,
+                expiration,
+                user_id,
+                ..
+            } => self
+                .trailing_stop_order(candle, OrderSide::Buy, stake, distance, expiration, user_id)
+                .map(Some),
+            CalculateCommand::SellTrailingStop {
+                stake,
+                distance,
+                expiration,
+                user_id,
+                ..
+            } => self
+                .trailing_stop_order(
+                    candle,
+                    OrderSide::Sell,
+                    stake,
+                    distance,
+                    expiration,
+                    user_id,
+                )
+                .map(Some),
+            CalculateCommand::None | CalculateCommand::Unknown => Ok(None),
+            CalculateCommand::CancelLimit { symbol, id } => {
+                self.cancel_order(symbol, id, candle);
+                Ok(None)
+            }
+            CalculateCommand::CancelAll {
+                symbol,
+                user_id,
+                side,
+            } => {
+                self.cancel_all(symbol, user_id, side, candle);
+                Ok(None)
+            }
+            CalculateCommand::AmendLimit {
+                symbol,
+                id,
+                price,
+                stake,
+                expiration,
+            } => self.amend_order(symbol, id, price, stake, expiration, candle),
+        }
+    }
+
+    /// Change a resting limit order in place, reserving or releasing only the difference,
+    /// a refused amendment is reported as rejected and leaves the resting order as it was
+    #[instrument(level = "debug", skip(self))]
+    fn amend_order(
+        &mut self,
+        symbol: Symbol,
+        id: OrderId,
+        price: Option<f32>,
+        stake: Option<Stake>,
+        expiration: Option<Option<TimeStamp>>,
+        candle: &C,
+    ) -> Result<Option<Order>, CalculateAgentError> {
+        let Some(order) = self
+            .queue_orders
+            .get(&symbol)
+            .and_then(|orders| orders.iter().find(|o| o.id == id))
+        else {
+            debug!(symbol = symbol, id = ?id, "amend order not found");
+            return Err(CalculateAgentError::OrderNotFound { id });
         };
 
-        let Some(order) = orders.iter().find(|o| o.id == id) else {
-            debug!(symbol = symbol, id = ?id, "cancel order not found");
-            return;
+        let order = order.clone();
+
+        if !matches!(order.order_type, OrderType::Limit | OrderType::StopLimit) {
+            let error = CalculateAgentError::NotLimitOrderAmend { id };
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        let mut amended = order.clone();
+        amended.price = price.unwrap_or(order.price);
+        amended.qty = stake
+            .map(|stake| {
+                self.get_stake_qty(stake, &order.side, order.order_type, amended.price, candle)
+            })
+            .unwrap_or(order.qty);
+        amended.expiration = expiration.unwrap_or(order.expiration);
+
+        if order.oco_id.is_some() || order.parent_id.is_some() {
+            // the legs of a pair share one reservation
+            let error = CalculateAgentError::LinkedOrderAmend { id };
+            return Err(self.reject_order(amended, candle, error));
+        }
+
+        if let Err(error) = self.apply_instrument(&mut amended) {
+            return Err(self.reject_order(amended, candle, error));
+        }
+
+        if amended.qty <= amended.filled_qty {
+            let error = CalculateAgentError::InvalidAmendQuantity {
+                filled: amended.filled_qty,
+                qty: amended.qty,
+            };
+
+            return Err(self.reject_order(amended, candle, error));
+        }
+
+        let reserved = match amended.side {
+            OrderSide::Buy => {
+                let reserved = get_notional(order.price, order.qty - order.filled_qty);
+                let required = get_notional(amended.price, amended.qty - amended.filled_qty);
+
+                if required > reserved {
+                    self.reserve_balance(required - reserved)
+                } else {
+                    self.balance += reserved - required;
+                    Ok(())
+                }
+            }
+            OrderSide::Sell => {
+                let qty = amended.qty - order.qty;
+
+                if qty > 0.0 {
+                    self.freeze_asset(order.id, candle, amended.price, qty)
+                } else {
+                    handle_release_asset!(self, order, -qty);
+                    handle_repay_borrowed!(self, symbol);
+                    Ok(())
+                }
+            }
         };
 
-        let executed_order = handle_cancel_order!(self, order, candle);
+        if let Err(error) = reserved {
+            return Err(self.reject_order(amended, candle, error));
+        }
 
-        self.executed_orders.push(executed_order);
+        amended.commission =
+            self.get_commission(amended.order_type, get_notional(amended.price, amended.qty));
 
-        orders.retain(|o| o.id != id);
+        if let Some(order) = self
+            .queue_orders
+            .get_mut(&symbol)
+            .and_then(|orders| orders.iter_mut().find(|o| o.id == id))
+        {
+            *order = amended.clone();
+        }
+
+        // the new price is checked against the next open again
+        if amended.time_in_force == TimeInForce::PostOnly && amended.price != order.price {
+            self.unposted.insert(id);
+        }
+
+        self.activate.on_order(candle.get_start_time(), &amended);
+
+        Ok(Some(amended))
     }
 
-    /// Get the result of the agent
+    /// Place a trailing stop order following the candle close
     #[instrument(level = "debug", skip(self))]
-    pub fn get_result(&self) -> CalculateResult {
-        debug!(
-            balance = self.balance,
-            queue = self
-                .queue_orders
-                .iter()
-                .fold(0, |acc, (_, v)| acc + v.len()),
-            "Agent get result"
-        );
+    fn trailing_stop_order(
+        &mut self,
+        candle: &C,
+        side: OrderSide,
+        stake: Stake,
+        distance: TrailingDistance,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    ) -> Result<Order, CalculateAgentError> {
+        let best_price = candle.get_close();
+        let stop_price = distance.get_stop_price(&side, best_price);
+        let qty = self.get_stake_qty(stake, &side, OrderType::TrailingStop, stop_price, candle);
 
-        CalculateResult {
-            balance: self.balance,
-            min_balance: self.min_balance,
-            opened_orders: self
-                .queue_orders
-                .iter()
-                .fold(0, |acc, (_, v)| acc + v.len()),
-            executed_orders: self.executed_orders.len(),
-            assets_available: self.portfolio_available.clone(),
-            assets_frozen: self.portfolio_frozen.clone(),
+        let mut order = Order {
+            id: Uuid::new_v4(),
+            created_at: candle.get_start_time(),
+            finished_at: 0,
+            symbol: candle.get_symbol(),
+            price: stop_price,
+            stop_price: Some(stop_price),
+            qty,
+            filled_qty: 0.0,
+            last_qty: 0.0,
+            commission: Amount::default(),
+            slippage: 0.0,
+            status: OrderStatus::Open,
+            reason: None,
+            side,
+            order_type: OrderType::TrailingStop,
+            expiration,
+            time_in_force: TimeInForce::GoodTillCancel,
+            user_id,
+            parent_id: None,
+            oco_id: None,
+            trailing: Some(Trailing {
+                distance,
+                best_price,
+            }),
+        };
+
+        if let Err(error) = self.apply_instrument(&mut order) {
+            return Err(self.reject_order(order, candle, error));
         }
+
+        order.commission =
+            self.get_commission(order.order_type, get_notional(order.price, order.qty));
+
+        let reserved = match order.side {
+            OrderSide::Buy => self.reserve_balance(get_notional(order.price, order.qty)),
+            OrderSide::Sell => self.freeze_asset(order.id, candle, order.price, order.qty),
+        };
+
+        if let Err(error) = reserved {
+            return Err(self.reject_order(order, candle, error));
+        }
+
+        self.activate.on_order(candle.get_start_time(), &order);
+
+        self.queue_orders
+            .entry(order.symbol.clone())
+            .or_default()
+            .push(order.clone());
+
+        Ok(order)
     }
 
-    /// Final action after all rounds finished
-    #[instrument(level = "debug", skip(self))]
-    pub fn on_end(&mut self) {
-        self.activate.on_end(self.get_result())
+    /// Place the legs of a market bracket entry right away, drop the bracket of a refused entry
+    fn perform_bracket(
+        &mut self,
+        id: OrderId,
+        entry: Result<Order, CalculateAgentError>,
+        candle: &C,
+    ) -> Result<Order, CalculateAgentError> {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                self.brackets.remove(&id);
+                return Err(error);
+            }
+        };
+
+        if entry.order_type == OrderType::Market {
+            let executed = self
+                .executed_orders
+                .iter()
+                .rev()
+                .find(|o| o.id == entry.id)
+                .cloned();
+
+            // the entry is placed either way, rejected legs are reported through on_order
+            if let Some(executed) = executed {
+                if let Err(e) = self.place_bracket_legs(&executed, candle) {
+                    warn!(error = ?e, order = ?executed, "Error placing bracket legs");
+                }
+            }
+        }
+
+        Ok(entry)
     }
 
-    /// Action after a round finished
+    /// Perform a candle
     #[instrument(level = "debug", skip(self))]
-    pub fn on_end_round(&mut self, _ts: u64, _candles: &[C]) {
-        self.min_balance = self.min_balance.min(self.balance);
-    }
-}
+    pub fn perform_candle(&mut self, candle: &C) {
+        let symbol = candle.get_symbol();
 
-#[cfg(test)]
-mod tests {
-    use crate::order::Order;
-    use crate::test_utils::{init_tracing, Candle};
-    use crate::{
-        assert_agent_state, buy_limit, buy_market, sell_limit, sell_market, Activate,
-        CalculateAgent, CalculateCommand, CalculateResult, Symbol,
-    };
-    use std::collections::HashMap;
-    use std::sync::Mutex;
-    use tracing::info;
+        self.prune_traded_notional(candle.get_start_time());
 
-    #[derive(Debug, Default)]
-    struct CalculateIterActivate {
-        orders: Mutex<Vec<Order>>,
-    }
+        let path = self.intrabar_path.get_path(
+            candle.get_open(),
+            candle.get_high(),
+            candle.get_low(),
+            candle.get_close(),
+            self.get_position(&symbol),
+            self.get_queued(&symbol),
+        );
 
-    impl Activate<Candle> for &CalculateIterActivate {
-        fn activate(
-            &self,
-            _candles: &[Candle],
-            _prices: &HashMap<Symbol, f32>,
-            _stats: &CalculateResult,
-            _active: &HashMap<Symbol, Vec<Order>>,
-        ) -> Vec<CalculateCommand> {
-            vec![CalculateCommand::None]
+        self.check_unposted_orders(candle);
+
+        let mut capacity = self.fill_model.get_capacity(candle);
+
+        for segment in path.windows(2) {
+            let (mut from, to) = (segment[0], segment[1]);
+
+            while let Some(orders) = self.queue_orders.get_mut(&symbol) {
+                let Some((index, point)) = get_next_touch(orders, from, to, self.touch_rule, |o| {
+                    is_fillable(o, capacity)
+                }) else {
+                    break;
+                };
+
+                from = point;
+
+                let order = &mut orders[index];
+
+                if order.order_type == OrderType::StopLimit {
+                    order.order_type = OrderType::Limit;
+
+                    debug!(order = ?order, "stop limit order triggered");
+
+                    self.activate.on_order(candle.get_start_time(), order);
+                    continue;
+                }
+
+                let mut qty = order.qty - order.filled_qty;
+
+                if order.order_type == OrderType::Limit {
+                    if let Some(capacity) = capacity.as_mut() {
+                        qty = qty.min(*capacity);
+                        *capacity -= qty;
+                    }
+                }
+
+                let mut price = get_fill_price(order, point);
+
+                if matches!(
+                    order.order_type,
+                    OrderType::StopMarket | OrderType::TrailingStop
+                ) {
+                    order.slippage = self.slippage.get_slippage(&order.side, price, qty, candle);
+
+                    price = match order.side {
+                        OrderSide::Buy => price + order.slippage,
+                        OrderSide::Sell => price - order.slippage,
+                    };
+                }
+
+                if order.side == OrderSide::Buy {
+                    // return the difference between the reserved and the fill price
+                    self.balance += get_notional(order.price, qty) - get_notional(price, qty);
+                }
+
+                // the resting order keeps its price for the remaining reservation
+                let mut execution = order.clone();
+
+                execution.price = price;
+                execution.commission = self.commission.get_commission(
+                    get_fill_liquidity(order, point),
+                    get_notional(price, qty),
+                    self.traded_notional
+                        .iter()
+                        .map(|(_, notional)| notional)
+                        .sum(),
+                );
+
+                let executed_order = match order.side {
+                    OrderSide::Buy => handle_buy_executed_order!(self, execution, candle, qty),
+                    OrderSide::Sell => handle_sell_executed_order!(self, execution, candle, qty),
+                };
+
+                self.executed_orders.push(executed_order.clone());
+
+                if executed_order.status == OrderStatus::Close {
+                    orders.remove(index);
+                    self.on_order_finished(&executed_order, candle);
+                } else {
+                    order.filled_qty = executed_order.filled_qty;
+                    order.status = executed_order.status.clone();
+
+                    // the first execution of a leg cancels the other one
+                    if let Some(oco_id) = order.oco_id.take() {
+                        self.cancel_linked_order(&symbol, oco_id, candle);
+                    }
+                }
+            }
         }
 
-        fn on_order(&mut self, _ts: u64, order: &Order) {
-            self.orders.lock().unwrap().push(order.clone());
-        }
+        let mut expired_orders = vec![];
+
+        if let Some(orders) = self.queue_orders.get_mut(&symbol) {
+            for order in orders.iter_mut() {
+                if order.order_type == OrderType::TrailingStop {
+                    ratchet_trailing_stop(order, candle);
+                }
+
+                let reason = match order.time_in_force {
+                    TimeInForce::ImmediateOrCancel => Some(OrderReason::ImmediateOrCancel),
+                    TimeInForce::FillOrKill => Some(OrderReason::FillOrKill),
+                    TimeInForce::GoodTillDate(ts) if ts < candle.get_start_time() => {
+                        Some(OrderReason::GoodTillDate)
+                    }
+                    _ => order
+                        .expiration
+                        .filter(|expiration| {
+                            order.created_at + expiration < candle.get_start_time()
+                        })
+                        .map(|_| OrderReason::Expiration),
+                };
+
+                if let Some(reason) = reason {
+                    order.reason = Some(reason);
+
+                    expired_orders.push(handle_cancel_order!(
+                        self,
+                        order,
+                        candle,
+                        OrderStatus::Expired
+                    ));
+                }
+            }
+
+            orders.retain(|o| !expired_orders.iter().any(|e| e.id == o.id));
+        }
+
+        for order in expired_orders {
+            self.executed_orders.push(order.clone());
+            self.on_order_finished(&order, candle);
+        }
+
+        self.accrue_interest(candle);
+        self.check_maintenance_margin(candle);
+
+        self.prices.insert(candle.get_symbol(), candle.get_close());
+
+        debug!(
+            symbol = candle.get_symbol(),
+            portfolio_available = ?self.portfolio_available.get(&candle.get_symbol()),
+            portfolio_frozen = ?self.portfolio_frozen.get(&candle.get_symbol()),
+            "perform_candle done"
+        );
+    }
+
+    /// Reject the post-only orders the open of their first candle would fill right away
+    #[instrument(level = "debug", skip(self))]
+    fn check_unposted_orders(&mut self, candle: &C) {
+        let Some(orders) = self.queue_orders.get_mut(&candle.get_symbol()) else {
+            return;
+        };
+
+        let mut rejected = vec![];
+
+        orders.retain(|order| {
+            if !self.unposted.remove(&order.id) {
+                return true;
+            }
+
+            match check_post_only(order, candle.get_open()) {
+                Ok(_) => true,
+                Err(error) => {
+                    rejected.push((order.clone(), error));
+                    false
+                }
+            }
+        });
+
+        for (mut order, error) in rejected {
+            order.reason = Some(OrderReason::Rejected(error));
+
+            let order = handle_cancel_order!(self, order, candle, OrderStatus::Rejected);
+
+            self.executed_orders.push(order.clone());
+            self.on_order_finished(&order, candle);
+        }
+    }
+
+    /// Check the margin for a short sell of the quantity the holdings don't cover
+    #[instrument(level = "debug", skip(self))]
+    fn check_margin(
+        &mut self,
+        candle: &C,
+        price: f32,
+        qty: f32,
+    ) -> Result<(), CalculateAgentError> {
+        let symbol = candle.get_symbol();
+
+        let Some(margin) = self.margin else {
+            return Err(CalculateAgentError::InsufficientAssetBalance {
+                available: *self.portfolio_available.get(&symbol).unwrap_or(&0.0),
+                symbol,
+                required: qty,
+            });
+        };
+
+        self.prices.entry(symbol.clone()).or_insert(price);
+
+        let equity = self.get_equity();
+        let required =
+            (self.get_liabilities() + self.get_short_reserved() + get_notional(price, qty))
+                * to_amount(margin.initial_margin);
+
+        if equity < required {
+            return Err(CalculateAgentError::InsufficientMargin {
+                symbol,
+                equity,
+                required,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Charge the borrow interest for the candle symbol
+    #[instrument(level = "debug", skip(self))]
+    fn accrue_interest(&mut self, candle: &C) {
+        let Some(margin) = self.margin else {
+            return;
+        };
+
+        let borrowed = *self
+            .portfolio_borrowed
+            .get(&candle.get_symbol())
+            .unwrap_or(&0.0);
+
+        if borrowed > 0.0 {
+            self.balance -=
+                get_notional(candle.get_close(), borrowed) * to_amount(margin.borrow_rate);
+        }
+    }
+
+    /// Liquidate the short of the candle symbol when the maintenance margin is breached
+    #[instrument(level = "debug", skip(self))]
+    fn check_maintenance_margin(&mut self, candle: &C) {
+        let Some(margin) = self.margin else {
+            return;
+        };
+
+        let symbol = candle.get_symbol();
+        let borrowed = *self.portfolio_borrowed.get(&symbol).unwrap_or(&0.0);
+
+        if borrowed <= 0.0 {
+            return;
+        }
+
+        // the worst price for a short within the candle
+        let previous = self.prices.insert(symbol.clone(), candle.get_high());
+        let equity = self.get_equity();
+        let liabilities = self.get_liabilities();
+
+        if let Some(previous) = previous {
+            self.prices.insert(symbol.clone(), previous);
+        }
+
+        if equity >= liabilities * to_amount(margin.maintenance_margin) {
+            return;
+        }
+
+        warn!(
+            symbol = symbol,
+            equity = %equity,
+            liabilities = %liabilities,
+            "maintenance margin breached, liquidating"
+        );
+
+        let ids = self
+            .queue_orders
+            .get(&symbol)
+            .map(|orders| {
+                orders
+                    .iter()
+                    .filter(|o| o.side == OrderSide::Sell)
+                    .map(|o| o.id)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        for id in ids {
+            self.cancel_order(symbol.clone(), id, candle);
+        }
+
+        let qty = *self.portfolio_borrowed.get(&symbol).unwrap_or(&0.0)
+            - *self.portfolio_available.get(&symbol).unwrap_or(&0.0);
+
+        if qty <= 0.0 {
+            handle_repay_borrowed!(self, symbol);
+            return;
+        }
+
+        let price = candle.get_high();
+        let order = Order {
+            created_at: candle.get_start_time(),
+            finished_at: 0,
+            price,
+            stop_price: None,
+            qty,
+            filled_qty: 0.0,
+            last_qty: 0.0,
+            symbol: symbol.clone(),
+            id: Uuid::new_v4(),
+            commission: self.get_commission(OrderType::Market, get_notional(price, qty)),
+            slippage: 0.0,
+            status: OrderStatus::Open,
+            reason: None,
+            side: OrderSide::Buy,
+            order_type: OrderType::Market,
+            expiration: None,
+            time_in_force: TimeInForce::GoodTillCancel,
+            user_id: None,
+            parent_id: None,
+            oco_id: None,
+            trailing: None,
+        };
+
+        self.balance -= get_notional(price, qty);
+
+        self.activate.on_order(candle.get_start_time(), &order);
+
+        let executed_order = handle_buy_executed_order!(self, order, candle);
+        self.executed_orders.push(executed_order);
+    }
+
+    /// Cash plus the marked value of all positions minus the borrowed assets
+    fn get_equity(&self) -> Amount {
+        self.get_equity_point(Default::default()).get_equity()
+    }
+
+    /// Cash held by the queued buy orders
+    fn get_reserved(&self) -> Amount {
+        let reserved = self
+            .queue_orders
+            .values()
+            .flatten()
+            .filter(|o| o.side == OrderSide::Buy)
+            .map(|o| get_notional(o.price, o.qty - o.filled_qty))
+            .sum::<Amount>();
+
+        reserved - self.shared_reserves.values().copied().sum::<Amount>()
+    }
+
+    /// Notional of the queued sells that borrow once they execute
+    fn get_short_reserved(&self) -> Amount {
+        self.queue_orders
+            .values()
+            .flatten()
+            .filter_map(|o| {
+                self.short_reserves
+                    .get(&o.id)
+                    .map(|short| get_notional(o.price, *short))
+            })
+            .sum()
+    }
+
+    /// Net quantity held in the symbol, negative for a short
+    fn get_position(&self, symbol: &Symbol) -> f32 {
+        self.portfolio_available.get(symbol).unwrap_or(&0.0)
+            + self.portfolio_frozen.get(symbol).unwrap_or(&0.0)
+            - self.portfolio_borrowed.get(symbol).unwrap_or(&0.0)
+    }
+
+    /// Net remaining quantity of the queued limit orders in the symbol, negative for selling
+    fn get_queued(&self, symbol: &Symbol) -> f32 {
+        self.queue_orders
+            .get(symbol)
+            .into_iter()
+            .flatten()
+            .filter(|o| o.order_type == OrderType::Limit)
+            .map(|o| match o.side {
+                OrderSide::Buy => o.qty - o.filled_qty,
+                OrderSide::Sell => o.filled_qty - o.qty,
+            })
+            .sum()
+    }
+
+    /// Marked value of the borrowed assets
+    fn get_liabilities(&self) -> Amount {
+        self.portfolio_borrowed
+            .iter()
+            .map(|(symbol, qty)| get_notional(*self.prices.get(symbol).unwrap_or(&0.0), *qty))
+            .sum()
+    }
+
+    /// Perform a cancel order
+    #[instrument(level = "debug", skip(self))]
+    fn cancel_order(&mut self, symbol: Symbol, id: OrderId, candle: &C) {
+        let Some(orders) = self.queue_orders.get_mut(&symbol) else {
+            debug!(symbol = symbol, "cancel order symbol not found");
+            return;
+        };
+
+        let Some(order) = orders.iter().find(|o| o.id == id) else {
+            debug!(symbol = symbol, id = ?id, "cancel order not found");
+            return;
+        };
+
+        let executed_order = handle_cancel_order!(self, order, candle);
+
+        orders.retain(|o| o.id != id);
+
+        self.executed_orders.push(executed_order.clone());
+
+        self.on_order_finished(&executed_order, candle);
+    }
+
+    /// Cancel every queued order matching the symbol, user and side filters
+    #[instrument(level = "debug", skip(self))]
+    fn cancel_all(
+        &mut self,
+        symbol: Option<Symbol>,
+        user_id: Option<UserId>,
+        side: Option<OrderSide>,
+        candle: &C,
+    ) {
+        let orders = self
+            .queue_orders
+            .values()
+            .flatten()
+            .filter(|o| symbol.as_ref().is_none_or(|symbol| &o.symbol == symbol))
+            .filter(|o| user_id.is_none() || o.user_id == user_id)
+            .filter(|o| side.as_ref().is_none_or(|side| &o.side == side))
+            .map(|o| (o.symbol.clone(), o.id))
+            .collect::<Vec<_>>();
+
+        for (symbol, id) in orders {
+            self.cancel_order(symbol, id, candle);
+        }
+    }
+
+    /// Get the result of the agent
+    #[instrument(level = "debug", skip(self))]
+    pub fn get_result(&self) -> CalculateResult {
+        debug!(
+            balance = %self.balance,
+            queue = self
+                .queue_orders
+                .iter()
+                .fold(0, |acc, (_, v)| acc + v.len()),
+            "Agent get result"
+        );
+
+        CalculateResult {
+            balance: self.balance,
+            min_equity: self.min_equity,
+            peak_equity: self.peak_equity,
+            opened_orders: self
+                .queue_orders
+                .iter()
+                .fold(0, |acc, (_, v)| acc + v.len()),
+            executed_orders: self.executed_orders.len(),
+            assets_available: self.portfolio_available.clone(),
+            assets_frozen: self.portfolio_frozen.clone(),
+            assets_borrowed: self.portfolio_borrowed.clone(),
+            positions: self.positions.clone(),
+            reserved: self.get_reserved(),
+            liabilities: self.get_liabilities(),
+            fee_balance: self.fee_balance,
+        }
+    }
+
+    /// Execution, cancel and expiry reports in the order they happened,
+    /// a partially filled order has a report for every execution
+    pub fn get_executed_orders(&self) -> &[Order] {
+        &self.executed_orders
+    }
+
+    /// Orders resting in the queue
+    pub fn get_open_orders(&self) -> impl Iterator<Item = &Order> {
+        self.queue_orders.values().flatten()
+    }
+
+    /// Reports of the executed orders matching the filter
+    pub fn find_executed_orders<'a>(
+        &'a self,
+        filter: &'a OrderFilter,
+    ) -> impl Iterator<Item = &'a Order> {
+        self.executed_orders.iter().filter(|o| filter.matches(o))
+    }
+
+    /// Resting orders matching the filter
+    pub fn find_open_orders<'a>(
+        &'a self,
+        filter: &'a OrderFilter,
+    ) -> impl Iterator<Item = &'a Order> {
+        self.get_open_orders().filter(|o| filter.matches(o))
+    }
+
+    /// Latest state of the order, whether it is resting or finished
+    pub fn get_order(&self, id: OrderId) -> Option<&Order> {
+        self.get_open_orders()
+            .find(|o| o.id == id)
+            .or_else(|| self.executed_orders.iter().rev().find(|o| o.id == id))
+    }
+
+    /// Round trip trades closed so far
+    pub fn get_trades(&self) -> &[Trade] {
+        self.ledger.get_trades()
+    }
+
+    /// Get the equity of the agent with the assets marked at the last prices
+    pub fn get_equity_point(&self, ts: TimeStamp) -> EquityPoint {
+        let get_value = |portfolio: &HashMap<Symbol, f32>| {
+            portfolio
+                .iter()
+                .map(|(symbol, qty)| get_notional(*self.prices.get(symbol).unwrap_or(&0.0), *qty))
+                .sum::<Amount>()
+        };
+
+        EquityPoint {
+            ts,
+            cash: self.balance,
+            reserved: self.get_reserved(),
+            positions: get_value(&self.portfolio_available) - get_value(&self.portfolio_borrowed),
+            frozen: get_value(&self.portfolio_frozen),
+        }
+    }
+
+    /// Final action after all rounds finished
+    #[instrument(level = "debug", skip(self))]
+    pub fn on_end(&mut self) {
+        self.activate.on_end(self.get_result())
+    }
+
+    /// Action after a round finished, marks the equity at the last prices
+    #[instrument(level = "debug", skip(self))]
+    pub fn on_end_round(&mut self, ts: u64, _candles: &[C]) -> EquityPoint {
+        let point = self.get_equity_point(ts);
+        let equity = point.get_equity();
+
+        self.min_equity = self.min_equity.min(equity);
+        self.peak_equity = self.peak_equity.max(equity);
+
+        self.activate.on_end_round(ts, &point);
+
+        point
+    }
+}
+
+/// Refuse a post-only order that would trade right away at the market price
+fn check_post_only(order: &Order, market: f32) -> Result<(), CalculateAgentError> {
+    let crossing = match order.side {
+        OrderSide::Buy => order.price >= market,
+        OrderSide::Sell => order.price <= market,
+    };
+
+    if order.time_in_force == TimeInForce::PostOnly && crossing {
+        return Err(CalculateAgentError::PostOnlyCrossing {
+            price: order.price,
+            market,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::useless_vec)]
+mod tests {
+    use super::CalculateAgentError;
+    use crate::order::{Order, OrderFilter, OrderReason, OrderSide, OrderStatus, OrderType};
+    use crate::test_utils::{init_tracing, Candle};
+    use crate::{
+        amend_limit, assert_agent_state, buy_bracket, buy_limit, buy_market, buy_stop,
+        buy_stop_limit, buy_trailing_stop, cancel_all, cancel_limit, from_amount, sell_bracket,
+        sell_limit, sell_market, sell_stop, sell_trailing_stop, to_amount, Activate,
+        CalculateAgent, CalculateCommand, CalculateResult, CommissionAsset, CommissionTier,
+        FixedSlippage, InstrumentSpec, IntrabarPath, LotMatching, MakerTakerCommission,
+        MarginConfig, NearestExtremeFirst, OpenHighLowClose, OpenLowHighClose, ParticipationFill,
+        Pessimistic, Position, RangeSlippage, Stake, Symbol, TieredCommission, TimeInForce,
+        TouchRule, TradeStats, TrailingDistance, VolumeSlippage,
+    };
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tracing::info;
+    use uuid::Uuid;
+
+    #[derive(Debug, Default)]
+    struct CalculateIterActivate {
+        orders: Mutex<Vec<Order>>,
+    }
+
+    impl Activate<Candle> for &CalculateIterActivate {
+        fn activate(
+            &self,
+            _candles: &[Candle],
+            _prices: &HashMap<Symbol, f32>,
+            _stats: &CalculateResult,
+            _active: &HashMap<Symbol, Vec<Order>>,
+        ) -> Vec<CalculateCommand> {
+            vec![CalculateCommand::None]
+        }
+
+        fn on_order(&mut self, _ts: u64, order: &Order) {
+            self.orders.lock().unwrap().push(order.clone());
+        }
+    }
+
+    #[test]
+    fn test_calculate_agent_market() {
+        init_tracing();
+
+        let activate = CalculateIterActivate::default();
+
+        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 0,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result =
+            agent.perform_order(buy_market!(symbol, 5.0, user_id = "buy_market"), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        agent.on_end_round(0, &vec![candle_1]);
+
+        let results = agent.get_result();
+
+        info!(results = ?results, "candle_1");
+
+        assert_agent_state!(results, 499.95, 0, 1, activate.orders, 2);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            sell_market!(symbol, 5.0, user_id = "sell_market"),
+            &candle_2,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_2);
+
+        agent.on_end_round(1, &vec![candle_2]);
+
+        let results = agent.get_result();
+
+        info!(results = ?results, "candle_2");
+        #[cfg(not(feature = "decimal"))]
+        assert_agent_state!(results, 1099.8899, 0, 2, activate.orders, 4);
+        // the decimal build keeps the exact cents the f32 one rounds away
+        #[cfg(feature = "decimal")]
+        assert_agent_state!(results, 1099.89, 0, 2, activate.orders, 4);
+    }
+
+    #[test]
+    fn test_calculate_agent_limit() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+
+        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 0,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_limit!(symbol, 5.0, 85.0, user_id = "buy_limit"),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_1");
+
+        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
+
+        let candle_2 = Candle {
+            symbol: "BTC".to_string(),
+            start_time: 1,
+            open: 120.0,
+            high: 130.0,
+            low: 80.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_2" );
+
+        assert_agent_state!(results, 574.9575, 0, 1, activate.orders, 2);
+
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+        );
+
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            sell_limit!(symbol, 5.0, 135.0, user_id = "sell_limit"),
+            &candle_3,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_3);
+
+        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_3");
+
+        assert_agent_state!(results, 574.9575, 1, 1, activate.orders, 3);
+
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+        assert_eq!(
+            results.assets_frozen,
+            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+        );
+
+        let candle_4 = Candle {
+            symbol: "BTC".to_string(),
+            start_time: 3,
+            open: 120.0,
+            high: 140.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_4);
+
+        agent.on_end_round(candle_4.start_time, &vec![candle_4]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_4");
+
+        assert_agent_state!(results, 1249.89, 0, 2, activate.orders, 4);
+
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+        assert_eq!(
+            results.assets_frozen,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+    }
+
+    #[test]
+    fn test_calculate_agent_buy_expiration() {
+        init_tracing();
+
+        let activate = CalculateIterActivate::default();
+
+        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 0,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            CalculateCommand::BuyLimit {
+                symbol: symbol.clone(),
+                price: 85.0,
+                stake: 5.0.into(),
+                expiration: Some(1),
+                time_in_force: TimeInForce::GoodTillCancel,
+                user_id: None,
+            },
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_1");
+
+        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
+
+        let candle_2 = Candle {
+            symbol: "BTC".to_string(),
+            start_time: 1,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_2" );
+
+        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
+
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_3);
+
+        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_3" );
+
+        assert_agent_state!(results, 1000.0, 0, 1, activate.orders, 2);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[1].status, OrderStatus::Expired);
+        assert_eq!(orders[1].reason, Some(OrderReason::Expiration));
+    }
+
+    #[test]
+    fn test_calculate_agent_sell_expiration() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            CalculateCommand::BuyMarket {
+                symbol: symbol.clone(),
+                stake: 5.0.into(),
+                user_id: None,
+            },
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let result = agent.perform_order(
+            CalculateCommand::SellLimit {
+                symbol: symbol.clone(),
+                stake: 5.0.into(),
+                price: 150.0,
+                expiration: Some(1),
+                time_in_force: TimeInForce::GoodTillCancel,
+                user_id: None,
+            },
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_1");
+
+        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
+
+        let candle_3 = Candle {
+            symbol: "BTC".to_string(),
+            start_time: 3,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_3);
+
+        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_3");
+
+        assert_eq!(from_amount(results.balance), 499.95);
+        assert_eq!(results.opened_orders, 0);
+        assert_eq!(results.executed_orders, 2);
+    }
+
+    #[test]
+    fn test_calculate_agent_buy_cancel() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 0,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            CalculateCommand::BuyLimit {
+                symbol: symbol.clone(),
+                price: 85.0,
+                stake: 5.0.into(),
+                expiration: None,
+                time_in_force: TimeInForce::GoodTillCancel,
+                user_id: None,
+            },
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let Ok(Some(Order { id, .. })) = result else {
+            panic!("Order not found");
+        };
+
+        agent.perform_candle(&candle_1);
+
+        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_1");
+
+        assert_eq!(from_amount(results.balance), 575.0);
+        assert_eq!(results.opened_orders, 1);
+        assert_eq!(results.executed_orders, 0);
+
+        let candle_2 = Candle {
+            symbol: "BTC".to_string(),
+            start_time: 1,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_2" );
+
+        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
+
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            CalculateCommand::CancelLimit {
+                symbol: symbol.clone(),
+                id,
+            },
+            &candle_3,
+        );
+
+        assert!(matches!(result, Ok(None)));
+
+        agent.perform_candle(&candle_3);
+
+        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+
+        let results = agent.get_result();
+
+        info!(result = ?agent.get_result(), "candle_3" );
+
+        assert_agent_state!(results, 1000.0, 0, 1, activate.orders, 2);
+    }
+
+    #[test]
+    fn test_calculate_agent_sell_cancel() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            CalculateCommand::BuyMarket {
+                symbol: symbol.clone(),
+                stake: 5.0.into(),
+                user_id: None,
+            },
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let result = agent.perform_order(
+            CalculateCommand::SellLimit {
+                symbol: symbol.clone(),
+                stake: 5.0.into(),
+                price: 150.0,
+                expiration: None,
+                time_in_force: TimeInForce::GoodTillCancel,
+                user_id: None,
+            },
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let Ok(Some(Order { id, .. })) = result else {
+            panic!("Order not found");
+        };
+
+        agent.perform_candle(&candle_1);
+
+        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_1");
+
+        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
+
+        let candle_3 = Candle {
+            symbol: "BTC".to_string(),
+            start_time: 3,
+            open: 120.0,
+            high: 130.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            CalculateCommand::CancelLimit {
+                symbol: symbol.clone(),
+                id,
+            },
+            &candle_3,
+        );
+
+        info!(result = ?result, "perform_order");
+
+        assert!(matches!(result, Ok(None)));
+
+        agent.perform_candle(&candle_3);
+
+        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_3");
+
+        assert_agent_state!(results, 499.95, 0, 2, activate.orders, 4);
+    }
+
+    #[test]
+    fn test_calculate_agent_short_without_margin() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(sell_market!(symbol, 5.0), &candle_1);
+
+        assert_eq!(
+            result.unwrap_err(),
+            CalculateAgentError::InsufficientAssetBalance {
+                symbol: symbol.clone(),
+                available: 0.0,
+                required: 5.0,
+            }
+        );
+
+        assert_agent_state!(agent.get_result(), 1000.0, 0, 0, activate.orders, 1);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[0].status, OrderStatus::Rejected);
+        assert!(matches!(
+            orders[0].reason,
+            Some(OrderReason::Rejected(
+                CalculateAgentError::InsufficientAssetBalance { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_calculate_agent_short_margin() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent =
+            CalculateAgent::new(1000.0, 0.0, Box::new(&activate)).with_margin(MarginConfig {
+                borrow_rate: 0.001,
+                ..Default::default()
+            });
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(sell_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_1");
+
+        assert_agent_state!(results, 1499.45, 0, 1, activate.orders, 2);
+        assert_eq!(from_amount(results.liabilities), 550.0);
+        assert_eq!(
+            results.assets_borrowed,
+            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+        );
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 90.0,
+            high: 100.0,
+            low: 80.0,
+            close: 95.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_2);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 1049.45, 0, 2, activate.orders, 4);
+        assert_eq!(from_amount(results.liabilities), 0.0);
+        assert_eq!(
+            results.assets_borrowed,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+    }
+
+    #[test]
+    fn test_calculate_agent_short_initial_margin() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(100.0, 0.0, Box::new(&activate))
+            .with_margin(MarginConfig::default());
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(sell_market!(symbol, 5.0), &candle_1);
+
+        assert_eq!(
+            result.unwrap_err(),
+            CalculateAgentError::InsufficientMargin {
+                symbol: symbol.clone(),
+                equity: to_amount(100.0),
+                required: to_amount(250.0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_calculate_agent_short_liquidation() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(100.0, 0.0, Box::new(&activate))
+            .with_margin(MarginConfig::default());
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 110.0,
+            low: 90.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(sell_market!(symbol, 1.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 200.0, 0, 1, activate.orders, 2);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 100.0,
+            high: 180.0,
+            low: 90.0,
+            close: 150.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 20.0, 0, 2, activate.orders, 4);
+        assert_eq!(from_amount(results.liabilities), 0.0);
+    }
+
+    #[test]
+    fn test_calculate_agent_short_limit_borrows_on_fill() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent =
+            CalculateAgent::new(1000.0, 0.0, Box::new(&activate)).with_margin(MarginConfig {
+                borrow_rate: 0.001,
+                ..Default::default()
+            });
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 120.0,
+            low: 90.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(sell_limit!(symbol, 5.0, 130.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        // the resting short holds its margin
+        let result = agent.perform_order(sell_limit!(symbol, 12.0, 130.0), &candle_1);
+
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InsufficientMargin { .. })
+        ));
+
+        agent.perform_candle(&candle_1);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_1");
+
+        assert_agent_state!(results, 1000.0, 1, 0, activate.orders, 2);
+        assert_eq!(from_amount(results.liabilities), 0.0);
+        assert_eq!(results.assets_borrowed.get(&symbol), None);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 120.0,
+            high: 135.0,
+            low: 115.0,
+            close: 130.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 1649.35, 0, 1, activate.orders, 3);
+        assert_eq!(from_amount(results.liabilities), 650.0);
+        assert_eq!(results.assets_borrowed.get(&symbol), Some(&5.0));
+        assert_eq!(results.assets_frozen.get(&symbol), Some(&0.0));
+    }
+
+    #[test]
+    fn test_calculate_agent_buy_stop() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_stop!(symbol, 2.0, 110.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let result = agent.perform_order(buy_stop!(symbol, 2.0, 130.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 520.0, 2, 0, activate.orders, 2);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 120.0,
+            high: 135.0,
+            low: 115.0,
+            close: 120.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 500.0, 0, 2, activate.orders, 4);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 4.0)])
+        );
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[2].price, 120.0);
+        assert_eq!(orders[3].price, 130.0);
+    }
+
+    #[test]
+    fn test_calculate_agent_sell_stop() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let result = agent.perform_order(
+            sell_stop!(symbol, 5.0, 90.0, user_id = "stop_loss"),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 500.0, 1, 1, activate.orders, 3);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 95.0,
+            high: 96.0,
+            low: 85.0,
+            close: 86.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 950.0, 0, 2, activate.orders, 4);
+
+        let orders = activate.orders.lock().unwrap();
+        let last = orders.last().unwrap();
+        assert_eq!(last.order_type, OrderType::StopMarket);
+        assert_eq!(last.status, OrderStatus::Close);
+        assert_eq!(last.price, 90.0);
+    }
+
+    #[test]
+    fn test_calculate_agent_buy_stop_limit() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_stop_limit!(symbol, 5.0, 110.0, 105.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 475.0, 1, 0, activate.orders, 1);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 107.0,
+            high: 111.0,
+            low: 106.0,
+            close: 108.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        assert_agent_state!(agent.get_result(), 475.0, 1, 0, activate.orders, 2);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders.last().unwrap().order_type, OrderType::Limit);
+        drop(orders);
+
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 108.0,
+            high: 109.0,
+            low: 104.0,
+            close: 106.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_3);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_3");
+
+        assert_agent_state!(results, 475.0, 0, 1, activate.orders, 3);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+        );
+    }
+
+    #[test]
+    fn test_calculate_agent_bracket_take_profit() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 5.0, take_profit = 110.0, stop_loss = 90.0),
+            &candle_1,
+        );
+
+        let Ok(Some(Order { id, .. })) = result else {
+            panic!("Order not found");
+        };
+
+        agent.perform_candle(&candle_1);
+
+        let results = agent.get_result();
+
+        assert_agent_state!(results, 500.0, 2, 1, activate.orders, 4);
+        assert_eq!(
+            results.assets_frozen,
+            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+        );
+
+        let orders = activate.orders.lock().unwrap();
+        let (take_profit, stop_loss) = (&orders[2], &orders[3]);
+        assert_eq!(take_profit.parent_id, Some(id));
+        assert_eq!(stop_loss.parent_id, Some(id));
+        assert_eq!(take_profit.oco_id, Some(stop_loss.id));
+        assert_eq!(stop_loss.oco_id, Some(take_profit.id));
+        drop(orders);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 104.0,
+            high: 112.0,
+            low: 100.0,
+            close: 108.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 1050.0, 0, 3, activate.orders, 6);
+        assert_eq!(
+            results.assets_frozen,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[4].status, OrderStatus::Close);
+        assert_eq!(orders[4].order_type, OrderType::Limit);
+        assert_eq!(orders[5].status, OrderStatus::Cancel);
+        assert_eq!(orders[5].order_type, OrderType::StopMarket);
+    }
+
+    #[test]
+    fn test_calculate_agent_bracket_stop_loss() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 96.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 5.0, 95.0, take_profit = 110.0, stop_loss = 90.0),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 525.0, 1, 0, activate.orders, 1);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 97.0,
+            high: 99.0,
+            low: 94.0,
+            close: 96.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        assert_agent_state!(agent.get_result(), 525.0, 2, 1, activate.orders, 4);
+
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 92.0,
+            high: 93.0,
+            low: 85.0,
+            close: 86.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_3);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_3");
+
+        assert_agent_state!(results, 975.0, 0, 3, activate.orders, 6);
+        assert_eq!(
+            results.assets_frozen,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[4].status, OrderStatus::Close);
+        assert_eq!(orders[4].order_type, OrderType::StopMarket);
+        assert_eq!(orders[5].status, OrderStatus::Cancel);
+        assert_eq!(orders[5].order_type, OrderType::Limit);
+    }
+
+    #[test]
+    fn test_calculate_agent_bracket_cancel() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 5.0, take_profit = 110.0, stop_loss = 90.0),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let take_profit_id = activate.orders.lock().unwrap()[2].id;
+
+        let result = agent.perform_order(
+            amend_limit!(symbol, take_profit_id, price = 120.0),
+            &candle_1,
+        );
+
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::LinkedOrderAmend { .. })
+        ));
+
+        let result = agent.perform_order(cancel_limit!(symbol, take_profit_id), &candle_1);
+
+        assert!(matches!(result, Ok(None)));
+
+        let results = agent.get_result();
+
+        assert_agent_state!(results, 500.0, 0, 3, activate.orders, 7);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+        );
+        assert_eq!(
+            results.assets_frozen,
+            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+        );
+    }
+
+    #[test]
+    fn test_calculate_agent_bracket_rejected_legs() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_margin(MarginConfig::default());
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        // the buy legs need more cash than the short entry brought in
+        let result = agent.perform_order(
+            sell_bracket!(symbol, 10.0, take_profit = 90.0, stop_loss = 400.0),
+            &candle_1,
+        );
+
+        let Ok(Some(entry)) = result else {
+            panic!("Entry not returned: {result:?}");
+        };
+
+        let results = agent.get_result();
+
+        assert_agent_state!(results, 2000.0, 0, 1, activate.orders, 4);
+        assert_eq!(
+            agent.get_order(entry.id).map(|o| &o.status),
+            Some(&OrderStatus::Close)
+        );
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[2].status, OrderStatus::Rejected);
+        assert_eq!(orders[3].status, OrderStatus::Rejected);
+    }
+
+    #[test]
+    fn test_calculate_agent_bracket_shared_reserve() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_margin(MarginConfig::default());
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            sell_bracket!(symbol, 10.0, take_profit = 90.0, stop_loss = 120.0),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        // only the stop loss notional is held for the two buy legs
+        let results = agent.get_result();
+
+        assert_agent_state!(results, 800.0, 2, 1, activate.orders, 4);
+        assert_eq!(from_amount(results.reserved), 1200.0);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 95.0,
+            high: 96.0,
+            low: 85.0,
+            close: 90.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        assert_agent_state!(results, 1100.0, 0, 3, activate.orders, 6);
+        assert_eq!(from_amount(results.reserved), 0.0);
+        assert_eq!(results.assets_borrowed.get(&symbol), Some(&0.0));
+
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 1.0, take_profit = 80.0, stop_loss = 90.0),
+            &candle_2,
+        );
+
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InvalidBracketPrices { .. })
+        ));
+
+        assert_agent_state!(agent.get_result(), 1100.0, 0, 3, activate.orders, 7);
+    }
+
+    #[test]
+    fn test_calculate_agent_bracket_base_commission() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.001, Box::new(&activate))
+            .with_commission_asset(CommissionAsset::Base);
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 1.0, take_profit = 110.0, stop_loss = 90.0),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let results = agent.get_result();
+
+        assert_eq!(results.opened_orders, 2);
+        assert_eq!(results.assets_frozen.get(&symbol), Some(&0.999));
+        assert_eq!(results.assets_available.get(&symbol), Some(&0.0));
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[2].status, OrderStatus::Open);
+        assert_eq!(orders[2].qty, 0.999);
+        assert_eq!(orders[3].status, OrderStatus::Open);
+        assert_eq!(orders[3].qty, 0.999);
+    }
+
+    #[test]
+    fn test_calculate_agent_sell_trailing_stop() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let result = agent.perform_order(
+            sell_trailing_stop!(symbol, 5.0, TrailingDistance::Absolute(10.0)),
+            &candle_1,
+        );
+
+        assert!(matches!(
+            result,
+            Ok(Some(Order {
+                stop_price: Some(90.0),
+                ..
+            }))
+        ));
+
+        agent.perform_candle(&candle_1);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 110.0,
+            high: 120.0,
+            low: 100.0,
+            close: 115.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        assert_agent_state!(agent.get_result(), 500.0, 1, 1, activate.orders, 3);
+
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 115.0,
+            high: 116.0,
+            low: 105.0,
+            close: 106.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_3);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_3");
+
+        assert_agent_state!(results, 1050.0, 0, 2, activate.orders, 4);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[3].order_type, OrderType::TrailingStop);
+        assert_eq!(orders[3].price, 110.0);
+    }
+
+    #[test]
+    fn test_calculate_agent_buy_trailing_stop() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 80.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_trailing_stop!(symbol, 5.0, TrailingDistance::Percent(0.1)),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 450.0, 1, 0, activate.orders, 1);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 85.0,
+            high: 95.0,
+            low: 84.0,
+            close: 90.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 560.0, 0, 1, activate.orders, 2);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+        );
+    }
+
+    fn perform_bracket_conflict(intrabar_path: Box<dyn IntrabarPath>) -> Vec<Order> {
+        let activate = CalculateIterActivate::default();
+        let mut agent =
+            CalculateAgent::new(1000.0, 0.0, Box::new(&activate)).with_intrabar_path(intrabar_path);
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 5.0, take_profit = 110.0, stop_loss = 90.0),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 100.0,
+            high: 115.0,
+            low: 88.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_eq!(results.opened_orders, 0);
+        assert_eq!(results.executed_orders, 3);
+
+        let orders = activate.orders.lock().unwrap().clone();
+        assert_eq!(orders.len(), 6);
+        assert_eq!(orders[4].status, OrderStatus::Close);
+        assert_eq!(orders[5].status, OrderStatus::Cancel);
+
+        orders
+    }
+
+    #[test]
+    fn test_calculate_agent_intrabar_path() {
+        init_tracing();
+
+        let orders = perform_bracket_conflict(Box::new(OpenHighLowClose));
+        assert_eq!(orders[4].order_type, OrderType::Limit);
+        assert_eq!(orders[4].price, 110.0);
+
+        let orders = perform_bracket_conflict(Box::new(OpenLowHighClose));
+        assert_eq!(orders[4].order_type, OrderType::StopMarket);
+        assert_eq!(orders[4].price, 90.0);
+
+        let orders = perform_bracket_conflict(Box::new(NearestExtremeFirst));
+        assert_eq!(orders[4].order_type, OrderType::StopMarket);
+
+        let orders = perform_bracket_conflict(Box::new(Pessimistic));
+        assert_eq!(orders[4].order_type, OrderType::StopMarket);
+    }
+
+    fn perform_flat_bracket(intrabar_path: Box<dyn IntrabarPath>) -> CalculateResult {
+        let activate = CalculateIterActivate::default();
+        let mut agent =
+            CalculateAgent::new(1000.0, 0.0, Box::new(&activate)).with_intrabar_path(intrabar_path);
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 102.0,
+            low: 96.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 1.0, 95.0, take_profit = 105.0, stop_loss = 90.0),
+            &candle_1,
+        );
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        // the low is nearer to the open
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 100.0,
+            high: 107.0,
+            low: 94.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        agent.get_result()
+    }
+
+    #[test]
+    fn test_calculate_agent_intrabar_path_flat() {
+        init_tracing();
+
+        // the entry fills on the way down and the take profit on the way up
+        let results = perform_flat_bracket(Box::new(NearestExtremeFirst));
+        assert_eq!(results.opened_orders, 0);
+        assert_eq!(results.executed_orders, 3);
+
+        // the high is visited before the long entry fills
+        let results = perform_flat_bracket(Box::new(Pessimistic));
+        assert_eq!(results.opened_orders, 2);
+        assert_eq!(results.executed_orders, 1);
+    }
+
+    #[test]
+    fn test_calculate_agent_limit_gap() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 105.0,
+            high: 110.0,
+            low: 101.0,
+            close: 105.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 5.0, 100.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 500.0, 1, 0, activate.orders, 1);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 95.0,
+            high: 98.0,
+            low: 93.0,
+            close: 97.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        assert_agent_state!(agent.get_result(), 525.0, 0, 1, activate.orders, 2);
+
+        let result = agent.perform_order(sell_limit!(symbol, 5.0, 100.0), &candle_2);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 104.0,
+            high: 106.0,
+            low: 102.0,
+            close: 103.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_3);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_3");
+
+        assert_agent_state!(results, 1045.0, 0, 2, activate.orders, 4);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[1].price, 95.0);
+        assert_eq!(orders[3].price, 104.0);
+    }
+
+    #[test]
+    fn test_calculate_agent_touch_rule() {
+        init_tracing();
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 90.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 5.0, 90.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 550.0, 1, 0, activate.orders, 1);
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_touch_rule(TouchRule::Inclusive);
+
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 5.0, 90.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        assert_agent_state!(agent.get_result(), 550.0, 0, 1, activate.orders, 2);
+    }
+
+    #[test]
+    fn test_calculate_agent_slippage() {
+        init_tracing();
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 120.0,
+            low: 80.0,
+            close: 110.0,
+            volume: Some(1000.0),
+        };
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_slippage(Box::new(FixedSlippage { bps: 10.0 }));
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(
+            result,
+            Ok(Some(Order {
+                price: 100.1,
+                slippage: 0.1,
+                ..
+            }))
+        ));
+
+        assert_agent_state!(agent.get_result(), 499.5, 0, 1, activate.orders, 2);
+
+        let result = agent.perform_order(sell_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(Order { price: 99.9, .. }))));
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_slippage(Box::new(RangeSlippage { fraction: 0.1 }));
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(Order { price: 104.0, .. }))));
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_slippage(Box::new(VolumeSlippage { impact: 0.5 }));
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(Order { price: 100.25, .. }))));
+    }
+
+    #[test]
+    fn test_calculate_agent_stop_slippage() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_slippage(Box::new(RangeSlippage { fraction: 0.1 }));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_stop!(symbol, 5.0, 110.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 105.0,
+            high: 120.0,
+            low: 100.0,
+            close: 115.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 440.0, 0, 1, activate.orders, 2);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[1].price, 112.0);
+        assert_eq!(orders[1].slippage, 2.0);
+    }
+
+    #[test]
+    fn test_calculate_agent_maker_taker_commission() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate)).with_commission(
+            Box::new(MakerTakerCommission {
+                maker: 0.001,
+                taker: 0.002,
+                fixed: 0.5,
+                minimum: 1.0,
+            }),
+        );
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert_eq!(
+            result.map(|o| o.map(|o| o.commission)),
+            Ok(Some(to_amount(1.5)))
+        );
+
+        let result = agent.perform_order(buy_market!(symbol, 0.1), &candle_1);
+
+        assert_eq!(
+            result.map(|o| o.map(|o| o.commission)),
+            Ok(Some(to_amount(1.0)))
+        );
+
+        let result = agent.perform_order(sell_limit!(symbol, 5.0, 110.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_1);
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 105.0,
+            high: 115.0,
+            low: 100.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 1036.45, 0, 3, activate.orders, 6);
+
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 5.0, 112.0), &candle_2);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        // the limit is crossed by the gap at the open and takes liquidity
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 108.0,
+            high: 115.0,
+            low: 105.0,
+            close: 110.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_3);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_3");
+
+        #[cfg(not(feature = "decimal"))]
+        assert_agent_state!(results, 494.86996, 0, 4, activate.orders, 8);
+        #[cfg(feature = "decimal")]
+        assert_agent_state!(results, 494.87, 0, 4, activate.orders, 8);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[5].commission, to_amount(1.05));
+        assert_eq!(orders[7].price, 108.0);
+        assert_eq!(orders[7].commission, to_amount(1.58));
+    }
+
+    #[test]
+    fn test_calculate_agent_tiered_commission() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(10000.0, 0.0, Box::new(&activate)).with_commission(
+            Box::new(TieredCommission {
+                tiers: vec![
+                    CommissionTier {
+                        min_traded: 0.0,
+                        maker: 0.002,
+                        taker: 0.004,
+                    },
+                    CommissionTier {
+                        min_traded: 1000.0,
+                        maker: 0.001,
+                        taker: 0.002,
+                    },
+                ],
+                window: 10,
+                ..Default::default()
+            }),
+        );
+
+        let symbol = "BTC".to_string();
+
+        let mut candle = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle);
+        assert_eq!(
+            result.map(|o| o.map(|o| o.commission)),
+            Ok(Some(to_amount(2.0)))
+        );
+
+        let result = agent.perform_order(buy_market!(symbol, 6.0), &candle);
+        assert_eq!(
+            result.map(|o| o.map(|o| o.commission)),
+            Ok(Some(to_amount(2.4)))
+        );
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle);
+        assert_eq!(
+            result.map(|o| o.map(|o| o.commission)),
+            Ok(Some(to_amount(1.0)))
+        );
+
+        candle.start_time = 20;
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle);
+        assert_eq!(
+            result.map(|o| o.map(|o| o.commission)),
+            Ok(Some(to_amount(2.0)))
+        );
+
+        // below every threshold the lowest tier applies
+        let mut agent = CalculateAgent::new(10000.0, 0.0, Box::new(&activate)).with_commission(
+            Box::new(TieredCommission {
+                tiers: vec![CommissionTier {
+                    min_traded: 1000.0,
+                    maker: 0.001,
+                    taker: 0.002,
+                }],
+                window: 10,
+                ..Default::default()
+            }),
+        );
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle);
+        assert_eq!(
+            result.map(|o| o.map(|o| o.commission)),
+            Ok(Some(to_amount(1.0)))
+        );
+    }
+
+    #[test]
+    fn test_calculate_agent_commission_asset() {
+        init_tracing();
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.001, Box::new(&activate))
+            .with_commission_asset(CommissionAsset::Base);
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let results = agent.get_result();
+
+        assert_agent_state!(results, 500.0, 0, 1, activate.orders, 2);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 4.995)])
+        );
+
+        let result = agent.perform_order(sell_market!(symbol, 4.995), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        assert_agent_state!(agent.get_result(), 999.0005, 0, 2, activate.orders, 4);
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.001, Box::new(&activate))
+            .with_commission_asset(CommissionAsset::Token)
+            .with_fee_balance(10.0);
+
+        let result = agent.perform_order(buy_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let result = agent.perform_order(sell_market!(symbol, 5.0), &candle_1);
+
+        assert!(matches!(result, Ok(Some(_))));
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_1");
+
+        assert_agent_state!(results, 1000.0, 0, 2, activate.orders, 4);
+        assert_eq!(from_amount(results.fee_balance), 9.0);
+
+        let result = agent.perform_order(buy_market!(symbol, 9500.0), &candle_1);
+
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InsufficientFeeBalance { .. })
+        ));
+
+        assert_agent_state!(agent.get_result(), 1000.0, 0, 2, activate.orders, 5);
+    }
+
+    #[test]
+    fn test_calculate_agent_partial_fill() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_fill_model(Box::new(ParticipationFill { rate: 0.1 }));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: Some(20.0),
+        };
+
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 5.0, 90.0), &candle_1);
+
+        let Ok(Some(Order { id, .. })) = result else {
+            panic!("Order not found");
+        };
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 95.0,
+            high: 100.0,
+            low: 85.0,
+            close: 90.0,
+            volume: Some(20.0),
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 550.0, 1, 1, activate.orders, 2);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 2.0)])
+        );
+
+        let filter = OrderFilter::default().with_status(OrderStatus::PartiallyFilled);
+        assert_eq!(agent.find_open_orders(&filter).count(), 1);
+
+        let Some(order) = agent.get_open_orders().find(|o| o.id == id) else {
+            panic!("Order not queued");
+        };
+
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+        assert_eq!(order.filled_qty, 2.0);
+
+        let candle_3 = Candle {
+            start_time: 3,
+            ..candle_2.clone()
+        };
+
+        agent.perform_candle(&candle_3);
+
+        let result = agent.perform_order(cancel_limit!(symbol, id), &candle_3);
+
+        assert!(matches!(result, Ok(None)));
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_3");
+
+        assert_agent_state!(results, 640.0, 0, 3, activate.orders, 4);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 4.0)])
+        );
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[1].status, OrderStatus::PartiallyFilled);
+        assert_eq!(orders[1].last_qty, 2.0);
+        assert_eq!(orders[2].status, OrderStatus::PartiallyFilled);
+        assert_eq!(orders[2].filled_qty, 4.0);
+        assert_eq!(orders[3].status, OrderStatus::Cancel);
+        assert_eq!(orders[3].filled_qty, 4.0);
     }
 
     #[test]
-    fn test_calculate_agent_market() {
+    fn test_calculate_agent_immediate_or_cancel() {
         init_tracing();
 
-        let activate = CalculateIterActivate::default();
-
-        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
-
         let symbol = "BTC".to_string();
 
         let candle_1 = Candle {
             symbol: symbol.clone(),
-            start_time: 0,
+            start_time: 1,
             open: 100.0,
-            high: 120.0,
-            low: 90.0,
-            close: 110.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: Some(20.0),
         };
 
-        let result =
-            agent.perform_order(buy_market!(symbol, 5.0, user_id = "buy_market"), &candle_1);
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 95.0,
+            high: 100.0,
+            low: 85.0,
+            close: 90.0,
+            volume: Some(20.0),
+        };
 
-        assert!(matches!(result, Ok(Some(_))));
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_fill_model(Box::new(ParticipationFill { rate: 0.1 }));
 
-        agent.perform_candle(&candle_1);
+        let result = agent.perform_order(
+            buy_limit!(
+                symbol.clone(),
+                5.0,
+                90.0,
+                time_in_force = TimeInForce::ImmediateOrCancel
+            ),
+            &candle_1,
+        );
 
-        agent.on_end_round(0, &vec![candle_1]);
+        assert!(matches!(result, Ok(Some(_))));
+
+        agent.perform_candle(&candle_2);
 
         let results = agent.get_result();
 
-        info!(results = ?results, "candle_1");
+        info!(result = ?results, "candle_2");
 
-        assert_agent_state!(results, 499.95, 0, 1, activate.orders, 2);
+        assert_agent_state!(results, 820.0, 0, 2, activate.orders, 3);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 2.0)])
+        );
 
-        let candle_2 = Candle {
-            symbol: symbol.clone(),
-            start_time: 1,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
-        };
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[2].status, OrderStatus::Expired);
+        assert_eq!(orders[2].filled_qty, 2.0);
+        assert_eq!(orders[2].reason, Some(OrderReason::ImmediateOrCancel));
+        drop(orders);
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_fill_model(Box::new(ParticipationFill { rate: 0.1 }));
 
         let result = agent.perform_order(
-            sell_market!(symbol, 5.0, user_id = "sell_market"),
-            &candle_2,
+            buy_limit!(
+                symbol.clone(),
+                5.0,
+                90.0,
+                time_in_force = TimeInForce::FillOrKill
+            ),
+            &candle_1,
         );
 
         assert!(matches!(result, Ok(Some(_))));
 
         agent.perform_candle(&candle_2);
 
-        agent.on_end_round(1, &vec![candle_2]);
-
         let results = agent.get_result();
 
-        info!(results = ?results, "candle_2");
-        assert_agent_state!(results, 1099.8899, 0, 2, activate.orders, 4);
+        info!(result = ?results, "candle_2");
+
+        assert_agent_state!(results, 1000.0, 0, 1, activate.orders, 2);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[1].status, OrderStatus::Expired);
+        assert_eq!(orders[1].filled_qty, 0.0);
+        assert_eq!(orders[1].reason, Some(OrderReason::FillOrKill));
     }
 
     #[test]
-    fn test_calculate_agent_limit() {
+    fn test_calculate_agent_good_till_date_and_post_only() {
         init_tracing();
         let activate = CalculateIterActivate::default();
-
-        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
 
         let symbol = "BTC".to_string();
 
         let candle_1 = Candle {
             symbol: symbol.clone(),
-            start_time: 0,
+            start_time: 1,
             open: 100.0,
-            high: 120.0,
-            low: 90.0,
-            close: 110.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
         };
 
         let result = agent.perform_order(
-            buy_limit!(symbol, 5.0, 85.0, user_id = "buy_limit"),
+            buy_limit!(
+                symbol.clone(),
+                1.0,
+                101.0,
+                time_in_force = TimeInForce::PostOnly
+            ),
             &candle_1,
         );
 
         assert!(matches!(result, Ok(Some(_))));
 
-        agent.perform_candle(&candle_1);
+        let result = agent.perform_order(
+            buy_limit!(
+                symbol.clone(),
+                1.0,
+                94.0,
+                time_in_force = TimeInForce::PostOnly
+            ),
+            &candle_1,
+        );
 
-        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+        assert!(matches!(result, Ok(Some(_))));
 
-        let results = agent.get_result();
+        let result = agent.perform_order(
+            buy_limit!(
+                symbol.clone(),
+                5.0,
+                80.0,
+                time_in_force = TimeInForce::GoodTillDate(2)
+            ),
+            &candle_1,
+        );
 
-        info!(result = ?agent.get_result(), "candle_1");
+        assert!(matches!(result, Ok(Some(_))));
 
-        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
+        assert_agent_state!(agent.get_result(), 405.0, 3, 0, activate.orders, 3);
 
+        // the first post-only order would take at the open, the second one rests and fills
         let candle_2 = Candle {
-            symbol: "BTC".to_string(),
-            start_time: 1,
-            open: 120.0,
-            high: 130.0,
-            low: 80.0,
-            close: 110.0,
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 95.0,
+            high: 100.0,
+            low: 85.0,
+            close: 90.0,
+            volume: None,
         };
 
         agent.perform_candle(&candle_2);
 
-        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+        assert_agent_state!(agent.get_result(), 506.0, 1, 2, activate.orders, 5);
 
-        let results = agent.get_result();
+        let candle_3 = Candle {
+            start_time: 3,
+            ..candle_2.clone()
+        };
 
-        info!(result = ?agent.get_result(), "candle_2" );
+        agent.perform_candle(&candle_3);
 
-        assert_agent_state!(results, 574.9575, 0, 1, activate.orders, 2);
+        assert_agent_state!(agent.get_result(), 906.0, 0, 3, activate.orders, 6);
 
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[3].status, OrderStatus::Rejected);
         assert_eq!(
-            results.assets_available,
-            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+            orders[3].reason,
+            Some(OrderReason::Rejected(
+                CalculateAgentError::PostOnlyCrossing {
+                    price: 101.0,
+                    market: 95.0
+                }
+            ))
         );
+        assert_eq!(orders[4].status, OrderStatus::Close);
+        assert_eq!(orders[4].price, 94.0);
+        assert_eq!(orders[5].status, OrderStatus::Expired);
+        assert_eq!(orders[5].reason, Some(OrderReason::GoodTillDate));
+    }
 
-        let candle_3 = Candle {
+    #[test]
+    fn test_calculate_agent_amend_limit() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let symbol = "BTC".to_string();
+
+        let candle_1 = Candle {
             symbol: symbol.clone(),
-            start_time: 3,
-            open: 120.0,
-            high: 130.0,
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 5.0, 90.0), &candle_1);
+
+        let Ok(Some(Order { id, .. })) = result else {
+            panic!("Order not found");
+        };
+
+        assert_agent_state!(agent.get_result(), 550.0, 1, 0, activate.orders, 1);
+
+        let result = agent.perform_order(
+            amend_limit!(symbol, id, price = 95.0, stake = 4.0),
+            &candle_1,
+        );
+
+        assert!(matches!(
+            result,
+            Ok(Some(Order {
+                price: 95.0,
+                qty: 4.0,
+                ..
+            }))
+        ));
+
+        assert_agent_state!(agent.get_result(), 620.0, 1, 0, activate.orders, 2);
+
+        let result = agent.perform_order(amend_limit!(symbol, id, stake = 20.0), &candle_1);
+
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InsufficientBalance { .. })
+        ));
+
+        assert_agent_state!(agent.get_result(), 620.0, 1, 0, activate.orders, 3);
+        assert_eq!(
+            activate.orders.lock().unwrap()[2].status,
+            OrderStatus::Rejected
+        );
+
+        let candle_2 = Candle {
+            symbol: symbol.clone(),
+            start_time: 2,
+            open: 100.0,
+            high: 105.0,
             low: 90.0,
-            close: 110.0,
+            close: 100.0,
+            volume: None,
+        };
+
+        agent.perform_candle(&candle_2);
+
+        let result = agent.perform_order(sell_limit!(symbol, 4.0, 110.0), &candle_2);
+
+        let Ok(Some(Order { id, .. })) = result else {
+            panic!("Order not found");
         };
 
         let result = agent.perform_order(
-            sell_limit!(symbol, 5.0, 135.0, user_id = "sell_limit"),
-            &candle_3,
+            amend_limit!(symbol, id, stake = 2.0, expiration = 10),
+            &candle_2,
         );
 
-        assert!(matches!(result, Ok(Some(_))));
+        assert!(matches!(
+            result,
+            Ok(Some(Order {
+                qty: 2.0,
+                expiration: Some(10),
+                ..
+            }))
+        ));
 
-        agent.perform_candle(&candle_3);
+        let result = agent.perform_order(amend_limit!(symbol, id, expiration = None), &candle_2);
 
-        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+        assert!(matches!(
+            result,
+            Ok(Some(Order {
+                qty: 2.0,
+                expiration: None,
+                ..
+            }))
+        ));
 
-        let results = agent.get_result();
+        let result = agent.perform_order(
+            amend_limit!(symbol, Uuid::new_v4(), price = 100.0),
+            &candle_2,
+        );
 
-        info!(result = ?agent.get_result(), "candle_3");
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::OrderNotFound { .. })
+        ));
 
-        assert_agent_state!(results, 574.9575, 1, 1, activate.orders, 3);
+        let result = agent.perform_order(buy_stop!(symbol, 1.0, 120.0), &candle_2);
+
+        let Ok(Some(Order { id: stop_id, .. })) = result else {
+            panic!("Order not found");
+        };
+
+        let result = agent.perform_order(amend_limit!(symbol, stop_id, price = 125.0), &candle_2);
+
+        assert_eq!(
+            result.unwrap_err(),
+            CalculateAgentError::NotLimitOrderAmend { id: stop_id }
+        );
+        assert_eq!(agent.get_order(stop_id).map(|o| o.price), Some(120.0));
+
+        let results = agent.get_result();
+
+        info!(result = ?results, "candle_2");
 
         assert_eq!(
             results.assets_available,
-            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+            HashMap::from_iter(vec![(symbol.to_string(), 2.0)])
         );
         assert_eq!(
             results.assets_frozen,
-            HashMap::from_iter(vec![(symbol.to_string(), 5.0)])
+            HashMap::from_iter(vec![(symbol.to_string(), 2.0)])
         );
 
-        let candle_4 = Candle {
-            symbol: "BTC".to_string(),
-            start_time: 3,
-            open: 120.0,
-            high: 140.0,
-            low: 90.0,
-            close: 110.0,
-        };
+        let result = agent.perform_order(
+            buy_limit!(
+                symbol.clone(),
+                1.0,
+                90.0,
+                time_in_force = TimeInForce::PostOnly
+            ),
+            &candle_2,
+        );
 
-        agent.perform_candle(&candle_4);
+        let Ok(Some(Order { id, .. })) = result else {
+            panic!("Order not found");
+        };
 
-        agent.on_end_round(candle_4.start_time, &vec![candle_4]);
+        let result = agent.perform_order(amend_limit!(symbol, id, price = 100.0), &candle_2);
 
-        let results = agent.get_result();
+        assert!(matches!(result, Ok(Some(Order { price: 100.0, .. }))));
 
-        info!(result = ?agent.get_result(), "candle_4");
+        // the amended price is checked against the next open
+        let candle_3 = Candle {
+            symbol: symbol.clone(),
+            start_time: 3,
+            open: 99.0,
+            high: 101.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
+        };
 
-        assert_agent_state!(results, 1249.89, 0, 2, activate.orders, 4);
+        agent.perform_candle(&candle_3);
 
         assert_eq!(
-            results.assets_available,
-            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+            agent.get_order(id).map(|o| &o.status),
+            Some(&OrderStatus::Rejected)
         );
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[1].id, orders[0].id);
+        assert_eq!(orders[1].status, OrderStatus::Open);
         assert_eq!(
-            results.assets_frozen,
-            HashMap::from_iter(vec![(symbol.to_string(), 0.0)])
+            orders.last().map(|o| (&o.status, &o.reason)),
+            Some((
+                &OrderStatus::Rejected,
+                &Some(OrderReason::Rejected(
+                    CalculateAgentError::PostOnlyCrossing {
+                        price: 100.0,
+                        market: 99.0
+                    }
+                ))
+            ))
         );
     }
 
     #[test]
-    fn test_calculate_agent_buy_expiration() {
+    fn test_calculate_agent_cancel_all() {
         init_tracing();
-
         let activate = CalculateIterActivate::default();
-
-        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
 
         let symbol = "BTC".to_string();
+        let other_symbol = "ETH".to_string();
 
         let candle_1 = Candle {
             symbol: symbol.clone(),
-            start_time: 0,
+            start_time: 1,
             open: 100.0,
-            high: 120.0,
-            low: 90.0,
-            close: 110.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
         };
 
-        let result = agent.perform_order(
-            CalculateCommand::BuyLimit {
-                symbol: symbol.clone(),
-                price: 85.0,
-                stake: 5.0,
-                expiration: Some(1),
-                user_id: None,
-            },
-            &candle_1,
-        );
-
-        assert!(matches!(result, Ok(Some(_))));
-
-        agent.perform_candle(&candle_1);
+        let other_candle_1 = Candle {
+            symbol: other_symbol.clone(),
+            start_time: 1,
+            open: 50.0,
+            high: 55.0,
+            low: 45.0,
+            close: 50.0,
+            volume: None,
+        };
 
-        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+        let commands = [
+            (buy_limit!(symbol.clone(), 2.0, 90.0), &candle_1),
+            (buy_market!(symbol, 2.0), &candle_1),
+            (sell_limit!(symbol, 2.0, 120.0), &candle_1),
+            (
+                buy_limit!(other_symbol, 2.0, 40.0, user_id = "user"),
+                &other_candle_1,
+            ),
+        ];
+
+        for (command, candle) in commands {
+            assert!(matches!(agent.perform_order(command, candle), Ok(Some(_))));
+        }
 
-        let results = agent.get_result();
+        assert_agent_state!(agent.get_result(), 540.0, 3, 1, activate.orders, 5);
 
-        info!(result = ?agent.get_result(), "candle_1");
+        let result = agent.perform_order(cancel_all!(user_id = "user"), &candle_1);
 
-        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
+        assert!(matches!(result, Ok(None)));
 
-        let candle_2 = Candle {
-            symbol: "BTC".to_string(),
-            start_time: 1,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
-        };
+        assert_agent_state!(agent.get_result(), 620.0, 2, 2, activate.orders, 6);
 
-        agent.perform_candle(&candle_2);
+        let result = agent.perform_order(cancel_all!(symbol, side = OrderSide::Sell), &candle_1);
 
-        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+        assert!(matches!(result, Ok(None)));
 
         let results = agent.get_result();
 
-        info!(result = ?agent.get_result(), "candle_2" );
-
-        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
-
-        let candle_3 = Candle {
-            symbol: symbol.clone(),
-            start_time: 3,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
-        };
-
-        agent.perform_candle(&candle_3);
+        assert_agent_state!(results, 620.0, 1, 3, activate.orders, 7);
+        assert_eq!(
+            results.assets_available,
+            HashMap::from_iter(vec![(symbol.to_string(), 2.0)])
+        );
 
-        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+        let result = agent.perform_order(cancel_all!(), &candle_1);
 
-        let results = agent.get_result();
+        assert!(matches!(result, Ok(None)));
 
-        info!(result = ?agent.get_result(), "candle_3" );
+        assert_agent_state!(agent.get_result(), 800.0, 0, 4, activate.orders, 8);
 
-        assert_agent_state!(results, 1000.0, 0, 1, activate.orders, 2);
+        let orders = activate.orders.lock().unwrap();
+        assert!(orders[5..].iter().all(|o| o.status == OrderStatus::Cancel));
     }
 
     #[test]
-    fn test_calculate_agent_sell_expiration() {
+    fn test_calculate_agent_stake() {
         init_tracing();
         let activate = CalculateIterActivate::default();
-        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_slippage(Box::new(FixedSlippage { bps: 100.0 }));
 
         let symbol = "BTC".to_string();
 
@@ -693,284 +4555,403 @@ mod tests {
             symbol: symbol.clone(),
             start_time: 1,
             open: 100.0,
-            high: 120.0,
-            low: 90.0,
-            close: 110.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
         };
 
-        let result = agent.perform_order(
-            CalculateCommand::BuyMarket {
-                symbol: symbol.clone(),
-                stake: 5.0,
-                user_id: None,
-            },
-            &candle_1,
-        );
+        let result = agent.perform_order(buy_market!(symbol, Stake::Quote(202.0)), &candle_1);
 
-        assert!(matches!(result, Ok(Some(_))));
+        assert!(matches!(
+            result,
+            Ok(Some(Order {
+                price: 101.0,
+                qty: 2.0,
+                ..
+            }))
+        ));
+
+        assert_agent_state!(agent.get_result(), 798.0, 0, 1, activate.orders, 2);
 
         let result = agent.perform_order(
-            CalculateCommand::SellLimit {
-                symbol: symbol.clone(),
-                stake: 5.0,
-                price: 150.0,
-                expiration: Some(1),
-                user_id: None,
-            },
+            buy_limit!(symbol.clone(), Stake::Quote(450.0), 90.0),
             &candle_1,
         );
 
-        assert!(matches!(result, Ok(Some(_))));
+        assert!(matches!(result, Ok(Some(Order { qty: 5.0, .. }))));
+
+        assert_agent_state!(agent.get_result(), 348.0, 1, 1, activate.orders, 3);
 
         agent.perform_candle(&candle_1);
 
-        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+        // 348 cash, 450 reserved and 2 BTC marked at 100
+        let result = agent.perform_order(sell_limit!(symbol, Stake::Equity(0.1), 99.8), &candle_1);
 
-        let results = agent.get_result();
+        assert!(matches!(result, Ok(Some(Order { qty: 1.0, .. }))));
+    }
 
-        info!(result = ?results, "candle_1");
+    #[test]
+    fn test_calculate_agent_instrument_spec() {
+        init_tracing();
 
-        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
+        let symbol = "BTC".to_string();
 
-        let candle_2 = Candle {
+        let spec = InstrumentSpec {
+            tick_size: Some(0.5),
+            step_size: Some(0.1),
+            min_qty: Some(0.1),
+            max_qty: Some(10.0),
+            min_notional: Some(10.0),
+            round: false,
+        };
+
+        let candle_1 = Candle {
             symbol: symbol.clone(),
-            start_time: 2,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
+            start_time: 1,
+            open: 100.0,
+            high: 105.0,
+            low: 95.0,
+            close: 100.0,
+            volume: None,
         };
 
-        agent.perform_candle(&candle_2);
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate))
+            .with_instrument(symbol.clone(), spec);
 
-        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 1.0, 90.3), &candle_1);
 
-        let results = agent.get_result();
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InvalidTickSize { .. })
+        ));
 
-        info!(result = ?results, "candle_2");
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 0.15, 90.0), &candle_1);
 
-        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InvalidStepSize { .. })
+        ));
 
-        let candle_3 = Candle {
-            symbol: "BTC".to_string(),
-            start_time: 3,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
-        };
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 0.1, 90.0), &candle_1);
 
-        agent.perform_candle(&candle_3);
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::NotionalBelowMinimum { .. })
+        ));
 
-        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 20.0, 90.0), &candle_1);
 
-        let results = agent.get_result();
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::QuantityAboveMaximum { .. })
+        ));
 
-        info!(result = ?agent.get_result(), "candle_3");
+        let result = agent.perform_order(
+            buy_trailing_stop!(symbol, 0.15, TrailingDistance::Absolute(5.0)),
+            &candle_1,
+        );
 
-        assert_eq!(results.balance, 499.95);
-        assert_eq!(results.opened_orders, 0);
-        assert_eq!(results.executed_orders, 2);
-    }
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InvalidStepSize { .. })
+        ));
 
-    #[test]
-    fn test_calculate_agent_buy_cancel() {
-        init_tracing();
-        let activate = CalculateIterActivate::default();
-        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+        assert_agent_state!(agent.get_result(), 1000.0, 0, 0, activate.orders, 5);
 
-        let symbol = "BTC".to_string();
+        // legs off the tick grid refuse the bracket before the entry is placed
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 1.0, take_profit = 110.3, stop_loss = 90.0),
+            &candle_1,
+        );
 
-        let candle_1 = Candle {
-            symbol: symbol.clone(),
-            start_time: 0,
-            open: 100.0,
-            high: 120.0,
-            low: 90.0,
-            close: 110.0,
-        };
+        assert!(matches!(
+            result,
+            Err(CalculateAgentError::InvalidTickSize { .. })
+        ));
 
-        let result = agent.perform_order(
-            CalculateCommand::BuyLimit {
-                symbol: symbol.clone(),
-                price: 85.0,
-                stake: 5.0,
-                expiration: None,
-                user_id: None,
+        assert_agent_state!(agent.get_result(), 1000.0, 0, 0, activate.orders, 6);
+
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[5].status, OrderStatus::Rejected);
+        assert!(matches!(
+            orders[5].reason,
+            Some(OrderReason::Rejected(
+                CalculateAgentError::InvalidTickSize { .. }
+            ))
+        ));
+        drop(orders);
+
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate)).with_instrument(
+            symbol.clone(),
+            InstrumentSpec {
+                round: true,
+                ..spec
             },
-            &candle_1,
         );
 
-        assert!(matches!(result, Ok(Some(_))));
+        let result = agent.perform_order(buy_limit!(symbol.clone(), 1.27, 90.3), &candle_1);
 
-        let Ok(Some(Order { id, .. })) = result else {
+        let Ok(Some(Order { price, qty, .. })) = result else {
             panic!("Order not found");
         };
 
-        agent.perform_candle(&candle_1);
+        assert_eq!(price, 90.5);
+        assert!((qty - 1.2).abs() < 1e-6);
 
-        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
+        let result = agent.perform_order(
+            buy_bracket!(symbol, 1.27, take_profit = 110.3, stop_loss = 90.0),
+            &candle_1,
+        );
 
-        let results = agent.get_result();
+        assert!(matches!(result, Ok(Some(_))));
 
-        info!(result = ?agent.get_result(), "candle_1");
+        let orders = activate.orders.lock().unwrap();
+        assert_eq!(orders[3].price, 110.5);
+        assert!((orders[3].qty - 1.2).abs() < 1e-6);
+        assert_eq!(orders[4].status, OrderStatus::Open);
+    }
 
-        assert_eq!(results.balance, 575.0);
-        assert_eq!(results.opened_orders, 1);
-        assert_eq!(results.executed_orders, 0);
+    #[test]
+    fn test_calculate_agent_position() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
 
-        let candle_2 = Candle {
-            symbol: "BTC".to_string(),
-            start_time: 1,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
+        let symbol = "BTC".to_string();
+
+        let candle = |start_time, open| Candle {
+            symbol: symbol.clone(),
+            start_time,
+            open,
+            high: open,
+            low: open,
+            close: open,
+            volume: None,
         };
 
-        agent.perform_candle(&candle_2);
+        let result = agent.perform_order(buy_market!(symbol.clone(), 2.0), &candle(1, 100.0));
+        assert!(matches!(result, Ok(Some(_))));
+        let result = agent.perform_order(buy_market!(symbol.clone(), 2.0), &candle(2, 110.0));
+        assert!(matches!(result, Ok(Some(_))));
 
-        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+        let result = agent.get_result();
+        let position = result.positions.get(&symbol).unwrap();
+        assert_eq!(position.qty, 4.0);
+        assert_eq!(position.entry_price, 105.0);
+        assert_eq!(from_amount(position.cost_basis), 420.0);
+        assert_eq!(from_amount(position.realized_pnl), 0.0);
 
-        let results = agent.get_result();
+        let result = agent.perform_order(sell_market!(symbol.clone(), 3.0), &candle(3, 120.0));
+        assert!(matches!(result, Ok(Some(_))));
 
-        info!(result = ?agent.get_result(), "candle_2" );
+        let result = agent.get_result();
+        let position = result.positions.get(&symbol).unwrap();
+        assert_eq!(position.qty, 1.0);
+        assert_eq!(position.entry_price, 105.0);
+        assert_eq!(from_amount(position.realized_pnl), 45.0);
+        assert_eq!(from_amount(position.get_unrealized_pnl(130.0)), 25.0);
 
-        assert_agent_state!(results, 575.0, 1, 0, activate.orders, 1);
+        let prices = HashMap::from([(symbol.clone(), 130.0)]);
+        assert_eq!(from_amount(result.get_unrealized_pnl(&prices)), 25.0);
+        assert_eq!(from_amount(result.get_equity(&prices)), 1070.0);
 
-        let candle_3 = Candle {
-            symbol: symbol.clone(),
-            start_time: 3,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
-        };
+        let result = agent.perform_order(sell_market!(symbol.clone(), 1.0), &candle(4, 100.0));
+        assert!(matches!(result, Ok(Some(_))));
 
-        let result = agent.perform_order(
-            CalculateCommand::CancelLimit {
-                symbol: symbol.clone(),
-                id,
-            },
-            &candle_3,
+        let result = agent.get_result();
+        assert_eq!(
+            result.positions.get(&symbol),
+            Some(&Position {
+                realized_pnl: crate::to_amount(40.0),
+                ..Position::default()
+            })
         );
+    }
 
-        assert!(matches!(result, Ok(None)));
+    #[test]
+    fn test_calculate_agent_trade_ledger() {
+        init_tracing();
 
-        agent.perform_candle(&candle_3);
+        let symbol = "BTC".to_string();
 
-        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+        let candle = |start_time, open| Candle {
+            symbol: symbol.clone(),
+            start_time,
+            open,
+            high: open,
+            low: open,
+            close: open,
+            volume: None,
+        };
 
-        let results = agent.get_result();
+        let cases = [
+            (
+                LotMatching::Fifo,
+                vec![
+                    (2.0, 100.0, 120.0, 40.0, 2),
+                    (1.0, 110.0, 120.0, 10.0, 1),
+                    (1.0, 110.0, 90.0, -20.0, 2),
+                ],
+            ),
+            (
+                LotMatching::Lifo,
+                vec![
+                    (2.0, 110.0, 120.0, 20.0, 1),
+                    (1.0, 100.0, 120.0, 20.0, 2),
+                    (1.0, 100.0, 90.0, -10.0, 3),
+                ],
+            ),
+            (
+                LotMatching::AverageCost,
+                vec![(3.0, 105.0, 120.0, 45.0, 2), (1.0, 105.0, 90.0, -15.0, 3)],
+            ),
+        ];
+
+        for (matching, expected) in cases {
+            let activate = CalculateIterActivate::default();
+            let mut agent =
+                CalculateAgent::new(1000.0, 0.0, Box::new(&activate)).with_lot_matching(matching);
+
+            let orders = [
+                (buy_market!(symbol.clone(), 2.0), candle(1, 100.0)),
+                (buy_market!(symbol.clone(), 2.0), candle(2, 110.0)),
+                (sell_market!(symbol.clone(), 3.0), candle(3, 120.0)),
+                (sell_market!(symbol.clone(), 1.0), candle(4, 90.0)),
+            ];
+
+            for (order, candle) in orders {
+                let result = agent.perform_order(order, &candle);
+                assert!(matches!(result, Ok(Some(_))));
+            }
 
-        info!(result = ?agent.get_result(), "candle_3" );
+            let trades = agent
+                .get_trades()
+                .iter()
+                .map(|trade| {
+                    (
+                        trade.qty,
+                        trade.entry_price,
+                        trade.exit_price,
+                        from_amount(trade.pnl),
+                        trade.holding_period,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            assert_eq!(trades, expected, "{matching:?}");
+
+            if matching != LotMatching::Fifo {
+                continue;
+            }
 
-        assert_agent_state!(results, 1000.0, 0, 1, activate.orders, 2);
+            let stats = TradeStats::from_trades(agent.get_trades());
+
+            assert_eq!(stats.trades, 3);
+            assert!((stats.win_rate - 2.0 / 3.0).abs() < 1e-6);
+            assert_eq!(stats.profit_factor, 2.5);
+            assert_eq!(stats.expectancy, 10.0);
+            assert_eq!(stats.average_win, 25.0);
+            assert_eq!(stats.average_loss, -20.0);
+            assert_eq!(stats.max_consecutive_losses, 1);
+        }
     }
 
     #[test]
-    fn test_calculate_agent_sell_cancel() {
+    fn test_calculate_agent_order_history() {
         init_tracing();
         let activate = CalculateIterActivate::default();
-        let mut agent = CalculateAgent::new(1000.0, 0.0001, Box::new(&activate));
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
 
         let symbol = "BTC".to_string();
 
-        let candle_1 = Candle {
+        let candle = |start_time| Candle {
             symbol: symbol.clone(),
-            start_time: 1,
+            start_time,
             open: 100.0,
-            high: 120.0,
-            low: 90.0,
-            close: 110.0,
+            high: 100.0,
+            low: 100.0,
+            close: 100.0,
+            volume: None,
         };
 
-        let result = agent.perform_order(
-            CalculateCommand::BuyMarket {
-                symbol: symbol.clone(),
-                stake: 5.0,
-                user_id: None,
-            },
-            &candle_1,
-        );
-
-        assert!(matches!(result, Ok(Some(_))));
-
-        let result = agent.perform_order(
-            CalculateCommand::SellLimit {
-                symbol: symbol.clone(),
-                stake: 5.0,
-                price: 150.0,
-                expiration: None,
-                user_id: None,
-            },
-            &candle_1,
-        );
-
-        assert!(matches!(result, Ok(Some(_))));
-
-        let Ok(Some(Order { id, .. })) = result else {
+        let Ok(Some(market)) =
+            agent.perform_order(buy_market!(symbol, 2.0, user_id = "a"), &candle(1))
+        else {
             panic!("Order not found");
         };
 
-        agent.perform_candle(&candle_1);
-
-        agent.on_end_round(candle_1.start_time, &vec![candle_1]);
-
-        let results = agent.get_result();
-
-        info!(result = ?results, "candle_1");
-
-        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
-
-        let candle_2 = Candle {
-            symbol: symbol.clone(),
-            start_time: 2,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
+        let Ok(Some(limit)) =
+            agent.perform_order(buy_limit!(symbol, 1.0, 90.0, user_id = "b"), &candle(1))
+        else {
+            panic!("Order not found");
         };
 
-        agent.perform_candle(&candle_2);
+        let result = agent.perform_order(sell_market!(symbol, 1.0), &candle(2));
+        assert!(matches!(result, Ok(Some(_))));
 
-        agent.on_end_round(candle_2.start_time, &vec![candle_2]);
+        assert_eq!(agent.get_executed_orders().len(), 2);
+        assert_eq!(agent.get_open_orders().count(), 1);
 
-        let results = agent.get_result();
+        let filter = OrderFilter::default().with_side(OrderSide::Sell);
+        assert_eq!(agent.find_executed_orders(&filter).count(), 1);
 
-        info!(result = ?results, "candle_2");
+        let filter = OrderFilter::default().with_time_range(2, 3);
+        assert_eq!(agent.find_executed_orders(&filter).count(), 1);
 
-        assert_agent_state!(results, 499.95, 1, 1, activate.orders, 3);
+        let filter = OrderFilter::default()
+            .with_symbol(symbol.clone())
+            .with_status(OrderStatus::Open)
+            .with_user_id("b".to_string());
+        assert_eq!(agent.find_open_orders(&filter).count(), 1);
 
-        let candle_3 = Candle {
-            symbol: "BTC".to_string(),
-            start_time: 3,
-            open: 120.0,
-            high: 130.0,
-            low: 90.0,
-            close: 110.0,
-        };
+        let filter = OrderFilter::default().with_user_id("a".to_string());
+        assert_eq!(agent.find_open_orders(&filter).count(), 0);
 
-        let result = agent.perform_order(
-            CalculateCommand::CancelLimit {
-                symbol: symbol.clone(),
-                id,
-            },
-            &candle_3,
+        assert_eq!(
+            agent.get_order(market.id).map(|o| &o.status),
+            Some(&OrderStatus::Close)
+        );
+        assert_eq!(
+            agent.get_order(limit.id).map(|o| &o.status),
+            Some(&OrderStatus::Open)
         );
+    }
 
-        info!(result = ?result, "perform_order");
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_calculate_agent_decimal_balance() {
+        init_tracing();
+        let activate = CalculateIterActivate::default();
+        let mut agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
 
-        assert!(matches!(result, Ok(None)));
+        let symbol = "BTC".to_string();
 
-        agent.perform_candle(&candle_3);
+        let buy_candle = Candle {
+            symbol: symbol.clone(),
+            start_time: 1,
+            open: 100.1,
+            high: 100.2,
+            low: 100.1,
+            close: 100.2,
+            volume: None,
+        };
 
-        agent.on_end_round(candle_3.start_time, &vec![candle_3]);
+        let sell_candle = Candle {
+            open: 100.2,
+            ..buy_candle.clone()
+        };
 
-        let results = agent.get_result();
+        for _ in 0..10 {
+            let result = agent.perform_order(buy_market!(symbol, 3.0), &buy_candle);
+            assert!(matches!(result, Ok(Some(_))));
 
-        info!(result = ?results, "candle_3");
+            let result = agent.perform_order(sell_market!(symbol, 3.0), &sell_candle);
+            assert!(matches!(result, Ok(Some(_))));
+        }
 
-        assert_agent_state!(results, 499.95, 0, 2, activate.orders, 4);
+        assert_eq!(agent.get_result().balance, crate::Amount::from(1003));
     }
 }
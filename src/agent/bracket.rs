@@ -0,0 +1,8 @@
+/// Exit prices of a bracket waiting for its entry order to be executed
+#[derive(Debug, Clone, Copy)]
+pub struct Bracket {
+    pub take_profit: f32,
+    pub stop_loss: f32,
+    /// Quantity the entry credited to the portfolio, net of a base asset commission
+    pub filled_qty: f32,
+}
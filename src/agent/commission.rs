@@ -0,0 +1,114 @@
+use crate::types::TimeStamp;
+use crate::{to_amount, Amount};
+
+/// Whether the order added liquidity to the book or took it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// Asset the commission is paid in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommissionAsset {
+    /// Deducted from the cash balance
+    #[default]
+    Quote,
+    /// Deducted from the asset received, the base asset for buys and the quote for sells
+    Base,
+    /// Deducted from a separate fee token balance valued in the quote currency,
+    /// orders it cannot cover are rejected and fills it cannot cover are paid in the quote
+    Token,
+}
+
+/// Fee schedule applied to every executed order
+pub trait CommissionModel: Send {
+    /// Commission for an order of `notional` value, `traded` is the notional traded within the window before it
+    fn get_commission(&self, liquidity: Liquidity, notional: Amount, traded: Amount) -> Amount;
+
+    /// Length of the rolling window the traded notional is summed over
+    fn get_window(&self) -> Option<TimeStamp> {
+        None
+    }
+}
+
+/// Same rate for every order
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlatCommission {
+    pub rate: f32,
+}
+
+impl CommissionModel for FlatCommission {
+    fn get_commission(&self, _liquidity: Liquidity, notional: Amount, _traded: Amount) -> Amount {
+        notional * to_amount(self.rate)
+    }
+}
+
+/// Separate rates for resting and taking orders plus a fixed fee per order
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MakerTakerCommission {
+    pub maker: f32,
+    pub taker: f32,
+    pub fixed: f32,
+    pub minimum: f32,
+}
+
+impl CommissionModel for MakerTakerCommission {
+    fn get_commission(&self, liquidity: Liquidity, notional: Amount, _traded: Amount) -> Amount {
+        let rate = match liquidity {
+            Liquidity::Maker => self.maker,
+            Liquidity::Taker => self.taker,
+        };
+
+        (notional * to_amount(rate) + to_amount(self.fixed)).max(to_amount(self.minimum))
+    }
+}
+
+/// Rates applied once the traded notional reaches `min_traded`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CommissionTier {
+    pub min_traded: f32,
+    pub maker: f32,
+    pub taker: f32,
+}
+
+/// Maker and taker rates depending on the notional traded within the rolling window,
+/// the lowest tier applies below every threshold
+#[derive(Debug, Default, Clone)]
+pub struct TieredCommission {
+    pub tiers: Vec<CommissionTier>,
+    pub window: TimeStamp,
+    pub fixed: f32,
+    pub minimum: f32,
+}
+
+impl CommissionModel for TieredCommission {
+    fn get_commission(&self, liquidity: Liquidity, notional: Amount, traded: Amount) -> Amount {
+        let tier = self
+            .tiers
+            .iter()
+            .filter(|tier| to_amount(tier.min_traded) <= traded)
+            .max_by(|a, b| a.min_traded.total_cmp(&b.min_traded))
+            .or_else(|| {
+                self.tiers
+                    .iter()
+                    .min_by(|a, b| a.min_traded.total_cmp(&b.min_traded))
+            });
+
+        let Some(tier) = tier else {
+            return to_amount(self.fixed.max(self.minimum));
+        };
+
+        MakerTakerCommission {
+            maker: tier.maker,
+            taker: tier.taker,
+            fixed: self.fixed,
+            minimum: self.minimum,
+        }
+        .get_commission(liquidity, notional, traded)
+    }
+
+    fn get_window(&self) -> Option<TimeStamp> {
+        Some(self.window)
+    }
+}
@@ -1,10 +1,13 @@
-use crate::Symbol;
+use crate::{Amount, OrderId, Symbol};
 use thiserror::Error;
 
-#[derive(Debug, Error, PartialEq)]
+#[derive(Debug, Clone, Error, PartialEq)]
 pub enum CalculateAgentError {
     #[error("Insufficient balance: available {available}, required {required}")]
-    InsufficientBalance { available: f32, required: f32 },
+    InsufficientBalance { available: Amount, required: Amount },
+
+    #[error("Insufficient fee balance: available {available}, required {required}")]
+    InsufficientFeeBalance { available: Amount, required: Amount },
 
     #[error("Insufficient asset balance for {symbol}: available {available}, required {required}")]
     InsufficientAssetBalance {
@@ -13,6 +16,70 @@ pub enum CalculateAgentError {
         required: f32,
     },
 
+    #[error("Insufficient margin for {symbol}: equity {equity}, required {required}")]
+    InsufficientMargin {
+        symbol: Symbol,
+        equity: Amount,
+        required: Amount,
+    },
+
+    #[error("Post-only order at {price} would cross the market at {market}")]
+    PostOnlyCrossing { price: f32, market: f32 },
+
+    #[error("Amended quantity {qty} does not exceed the filled quantity {filled}")]
+    InvalidAmendQuantity { filled: f32, qty: f32 },
+
+    #[error("Bracket take-profit {take_profit} and stop-loss {stop_loss} do not surround the entry price {price}")]
+    InvalidBracketPrices {
+        price: f32,
+        take_profit: f32,
+        stop_loss: f32,
+    },
+
+    #[error("Order {id} is not queued")]
+    OrderNotFound { id: OrderId },
+
+    #[error("Order {id} is not a limit order and cannot be amended")]
+    NotLimitOrderAmend { id: OrderId },
+
+    #[error("Order {id} is a leg of a linked pair and cannot be amended")]
+    LinkedOrderAmend { id: OrderId },
+
+    #[error("Price {price} of {symbol} is not a multiple of the tick size {tick_size}")]
+    InvalidTickSize {
+        symbol: Symbol,
+        price: f32,
+        tick_size: f32,
+    },
+
+    #[error("Quantity {qty} of {symbol} is not a multiple of the step size {step_size}")]
+    InvalidStepSize {
+        symbol: Symbol,
+        qty: f32,
+        step_size: f32,
+    },
+
+    #[error("Quantity {qty} of {symbol} is below the minimum {min_qty}")]
+    QuantityBelowMinimum {
+        symbol: Symbol,
+        qty: f32,
+        min_qty: f32,
+    },
+
+    #[error("Quantity {qty} of {symbol} is above the maximum {max_qty}")]
+    QuantityAboveMaximum {
+        symbol: Symbol,
+        qty: f32,
+        max_qty: f32,
+    },
+
+    #[error("Notional {notional} of {symbol} is below the minimum {min_notional}")]
+    NotionalBelowMinimum {
+        symbol: Symbol,
+        notional: f32,
+        min_notional: f32,
+    },
+
     #[error("Unknown command")]
     UnknownCommand,
 }
@@ -0,0 +1,30 @@
+use crate::candle::CandleTrait;
+
+/// Liquidity available to resting limit orders within a candle
+pub trait FillModel<C>: Send {
+    /// Quantity the limit orders of the candle symbol can fill in total, `None` for no limit
+    fn get_capacity(&self, candle: &C) -> Option<f32>;
+}
+
+/// Touched limit orders are filled completely
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FullFill;
+
+impl<C> FillModel<C> for FullFill {
+    fn get_capacity(&self, _candle: &C) -> Option<f32> {
+        None
+    }
+}
+
+/// Limit orders take at most `rate` of the candle volume, the rest stays in the queue,
+/// candles without volume fill completely
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ParticipationFill {
+    pub rate: f32,
+}
+
+impl<C: CandleTrait> FillModel<C> for ParticipationFill {
+    fn get_capacity(&self, candle: &C) -> Option<f32> {
+        candle.get_volume().map(|volume| volume * self.rate)
+    }
+}
@@ -0,0 +1,133 @@
+use crate::agent::Liquidity;
+use crate::candle::CandleTrait;
+use crate::order::{Order, OrderSide, OrderType, TimeInForce};
+
+/// Whether a limit order is filled when the price only touches its level
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TouchRule {
+    /// The price has to trade through the limit
+    #[default]
+    Exclusive,
+    /// Trading at the limit is enough
+    Inclusive,
+}
+
+/// Price level a resting order reacts to
+struct Trigger {
+    level: f32,
+    /// The price has to fall to the level, otherwise it has to rise
+    falling: bool,
+    /// Touching the level is enough, otherwise the price has to cross it
+    inclusive: bool,
+}
+
+fn get_trigger(order: &Order, touch_rule: TouchRule) -> Option<Trigger> {
+    let limit_inclusive = touch_rule == TouchRule::Inclusive;
+
+    let (level, falling, inclusive) = match (order.order_type, &order.side) {
+        (OrderType::Market, _) => return None,
+        (OrderType::Limit, OrderSide::Buy) => (order.price, true, limit_inclusive),
+        (OrderType::Limit, OrderSide::Sell) => (order.price, false, limit_inclusive),
+        (_, OrderSide::Buy) => (order.stop_price?, false, true),
+        (_, OrderSide::Sell) => (order.stop_price?, true, true),
+    };
+
+    Some(Trigger {
+        level,
+        falling,
+        inclusive,
+    })
+}
+
+/// Point of the path segment where the order is touched, if it is touched at all
+pub fn get_touch_point(order: &Order, from: f32, to: f32, touch_rule: TouchRule) -> Option<f32> {
+    let trigger = get_trigger(order, touch_rule)?;
+
+    let reached = |price: f32| match (trigger.falling, trigger.inclusive) {
+        (true, true) => price <= trigger.level,
+        (true, false) => price < trigger.level,
+        (false, true) => price >= trigger.level,
+        (false, false) => price > trigger.level,
+    };
+
+    if reached(from) {
+        Some(from)
+    } else if reached(to) {
+        Some(trigger.level)
+    } else {
+        None
+    }
+}
+
+/// The first order touched while the price moves from `from` to `to`,
+/// orders rejected by `fillable` are skipped
+pub fn get_next_touch(
+    orders: &[Order],
+    from: f32,
+    to: f32,
+    touch_rule: TouchRule,
+    fillable: impl Fn(&Order) -> bool,
+) -> Option<(usize, f32)> {
+    orders
+        .iter()
+        .enumerate()
+        .filter(|(_, order)| fillable(order))
+        .filter_map(|(index, order)| {
+            get_touch_point(order, from, to, touch_rule).map(|point| (index, point))
+        })
+        .min_by(|(_, a), (_, b)| (a - from).abs().total_cmp(&(b - from).abs()))
+}
+
+/// Whether the limit quantity left within the candle allows filling the order,
+/// a fill-or-kill order needs its whole remaining quantity
+pub fn is_fillable(order: &Order, capacity: Option<f32>) -> bool {
+    if order.order_type != OrderType::Limit {
+        return true;
+    }
+
+    match (capacity, order.time_in_force) {
+        (None, _) => true,
+        (Some(capacity), TimeInForce::FillOrKill) => capacity >= order.qty - order.filled_qty,
+        (Some(capacity), _) => capacity > 0.0,
+    }
+}
+
+/// Price the touched order is filled at, a limit gapped through is filled at the better price
+pub fn get_fill_price(order: &Order, point: f32) -> f32 {
+    match (order.order_type, &order.side) {
+        (OrderType::Limit, OrderSide::Buy) => order.price.min(point),
+        (OrderType::Limit, OrderSide::Sell) => order.price.max(point),
+        _ => point,
+    }
+}
+
+/// A limit order filled at its own price rested on the book, a limit crossed by the point,
+/// e.g. by a gap at the open, and every other order type took liquidity
+pub fn get_fill_liquidity(order: &Order, point: f32) -> Liquidity {
+    match order.order_type {
+        OrderType::Limit if get_fill_price(order, point) == order.price => Liquidity::Maker,
+        _ => Liquidity::Taker,
+    }
+}
+
+/// Move the stop price of a trailing order after the best price seen within the candle
+pub fn ratchet_trailing_stop<C: CandleTrait>(order: &mut Order, candle: &C) {
+    let Some(trailing) = order.trailing.as_mut() else {
+        return;
+    };
+
+    trailing.best_price = match order.side {
+        OrderSide::Buy => trailing.best_price.min(candle.get_low()),
+        OrderSide::Sell => trailing.best_price.max(candle.get_high()),
+    };
+
+    let stop_price = trailing
+        .distance
+        .get_stop_price(&order.side, trailing.best_price);
+
+    order.stop_price = match (&order.side, order.stop_price) {
+        (OrderSide::Buy, Some(current)) => Some(current.min(stop_price)),
+        (OrderSide::Sell, Some(current)) => Some(current.max(stop_price)),
+        (_, None) => Some(stop_price),
+    };
+}
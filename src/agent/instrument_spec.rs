@@ -0,0 +1,99 @@
+use crate::agent::CalculateAgentError;
+use crate::types::Symbol;
+
+/// Share of a tick or step a value may be off the grid and still count as on it
+const GRID_TOLERANCE: f32 = 1e-3;
+
+/// Trading rules of a symbol, `None` disables the rule
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InstrumentSpec {
+    /// Price increment of limit and stop prices
+    pub tick_size: Option<f32>,
+    /// Quantity increment
+    pub step_size: Option<f32>,
+    pub min_qty: Option<f32>,
+    pub max_qty: Option<f32>,
+    /// Minimum price times quantity
+    pub min_notional: Option<f32>,
+    /// Round prices to the nearest tick and quantities down to the step
+    /// instead of rejecting orders off the grid
+    pub round: bool,
+}
+
+impl InstrumentSpec {
+    /// Price on the tick grid
+    pub fn get_price(&self, symbol: &Symbol, price: f32) -> Result<f32, CalculateAgentError> {
+        let Some(tick_size) = self.tick_size else {
+            return Ok(price);
+        };
+
+        let ticks = price / tick_size;
+
+        if self.round || (ticks - ticks.round()).abs() <= GRID_TOLERANCE {
+            return Ok(ticks.round() * tick_size);
+        }
+
+        Err(CalculateAgentError::InvalidTickSize {
+            symbol: symbol.clone(),
+            price,
+            tick_size,
+        })
+    }
+
+    /// Quantity on the step grid
+    pub fn get_qty(&self, symbol: &Symbol, qty: f32) -> Result<f32, CalculateAgentError> {
+        let Some(step_size) = self.step_size else {
+            return Ok(qty);
+        };
+
+        let steps = qty / step_size;
+
+        if (steps - steps.round()).abs() <= GRID_TOLERANCE {
+            return Ok(steps.round() * step_size);
+        }
+
+        if self.round {
+            return Ok(steps.floor() * step_size);
+        }
+
+        Err(CalculateAgentError::InvalidStepSize {
+            symbol: symbol.clone(),
+            qty,
+            step_size,
+        })
+    }
+
+    /// Check the quantity and notional limits
+    pub fn check(&self, symbol: &Symbol, price: f32, qty: f32) -> Result<(), CalculateAgentError> {
+        let min_qty = self.min_qty.unwrap_or(0.0);
+
+        if qty <= 0.0 || qty < min_qty {
+            return Err(CalculateAgentError::QuantityBelowMinimum {
+                symbol: symbol.clone(),
+                qty,
+                min_qty,
+            });
+        }
+
+        if let Some(max_qty) = self.max_qty.filter(|max_qty| qty > *max_qty) {
+            return Err(CalculateAgentError::QuantityAboveMaximum {
+                symbol: symbol.clone(),
+                qty,
+                max_qty,
+            });
+        }
+
+        if let Some(min_notional) = self
+            .min_notional
+            .filter(|min_notional| price * qty < *min_notional)
+        {
+            return Err(CalculateAgentError::NotionalBelowMinimum {
+                symbol: symbol.clone(),
+                notional: price * qty,
+                min_notional,
+            });
+        }
+
+        Ok(())
+    }
+}
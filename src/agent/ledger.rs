@@ -0,0 +1,152 @@
+use crate::order::{Order, OrderSide};
+use crate::types::{Symbol, TimeStamp};
+use crate::{from_amount, get_notional, to_amount, Amount};
+use std::collections::{HashMap, VecDeque};
+
+/// Which open entries an exit is matched against
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LotMatching {
+    /// The oldest entry first
+    #[default]
+    Fifo,
+    /// The newest entry first
+    Lifo,
+    /// All entries merged at their average price
+    AverageCost,
+}
+
+/// Round trip of an entry closed by an exit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub symbol: Symbol,
+    /// Side of the entry, a sell entry is a short
+    pub side: OrderSide,
+    pub qty: f32,
+    pub entry_time: TimeStamp,
+    pub exit_time: TimeStamp,
+    pub entry_price: f32,
+    pub exit_price: f32,
+    /// Entry and exit commissions of the matched quantity
+    pub fees: Amount,
+    /// Profit net of the fees
+    pub pnl: Amount,
+    /// Profit relative to the entry value
+    pub return_rate: f32,
+    pub holding_period: TimeStamp,
+}
+
+/// Open entry waiting for an exit
+#[derive(Debug, Clone)]
+struct Lot {
+    side: OrderSide,
+    qty: f32,
+    price: f32,
+    /// Commission of the remaining quantity
+    fee: Amount,
+    ts: TimeStamp,
+}
+
+/// Executions paired into round trip trades
+#[derive(Debug, Default, Clone)]
+pub struct Ledger {
+    matching: LotMatching,
+    lots: HashMap<Symbol, VecDeque<Lot>>,
+    trades: Vec<Trade>,
+}
+
+impl Ledger {
+    pub fn new(matching: LotMatching) -> Self {
+        Ledger {
+            matching,
+            ..Default::default()
+        }
+    }
+
+    pub fn get_trades(&self) -> &[Trade] {
+        &self.trades
+    }
+
+    /// Apply an execution of `qty` of the order, closing opposite entries first
+    pub fn apply(&mut self, order: &Order, qty: f32, ts: TimeStamp) {
+        if qty <= 0.0 {
+            return;
+        }
+
+        let lots = self.lots.entry(order.symbol.clone()).or_default();
+        let mut remaining = qty;
+
+        while remaining > 0.0 {
+            let index = match self.matching {
+                LotMatching::Lifo => lots.len().checked_sub(1),
+                _ => (!lots.is_empty()).then_some(0),
+            };
+
+            let Some(index) = index else {
+                break;
+            };
+
+            // open lots of a symbol always share the side
+            let lot = &mut lots[index];
+
+            if lot.side == order.side {
+                break;
+            }
+
+            let matched = lot.qty.min(remaining);
+            let fees = lot.fee * to_amount(matched) / to_amount(lot.qty)
+                + order.commission * to_amount(matched) / to_amount(qty);
+
+            let pnl = match lot.side {
+                OrderSide::Buy => {
+                    get_notional(order.price, matched) - get_notional(lot.price, matched)
+                }
+                OrderSide::Sell => {
+                    get_notional(lot.price, matched) - get_notional(order.price, matched)
+                }
+            } - fees;
+
+            self.trades.push(Trade {
+                symbol: order.symbol.clone(),
+                side: lot.side.clone(),
+                qty: matched,
+                entry_time: lot.ts,
+                exit_time: ts,
+                entry_price: lot.price,
+                exit_price: order.price,
+                fees,
+                pnl,
+                return_rate: from_amount(pnl) / (lot.price * matched),
+                holding_period: ts - lot.ts,
+            });
+
+            lot.fee -= lot.fee * to_amount(matched) / to_amount(lot.qty);
+            lot.qty -= matched;
+            remaining -= matched;
+
+            if lot.qty <= 0.0 {
+                lots.remove(index);
+            }
+        }
+
+        if remaining <= 0.0 {
+            return;
+        }
+
+        let fee = order.commission * to_amount(remaining) / to_amount(qty);
+
+        match (self.matching, lots.back_mut()) {
+            (LotMatching::AverageCost, Some(lot)) => {
+                lot.price = (lot.price * lot.qty + order.price * remaining) / (lot.qty + remaining);
+                lot.qty += remaining;
+                lot.fee += fee;
+            }
+            _ => lots.push_back(Lot {
+                side: order.side.clone(),
+                qty: remaining,
+                price: order.price,
+                fee,
+                ts,
+            }),
+        }
+    }
+}
@@ -1,22 +1,63 @@
 #[macro_export]
 macro_rules! handle_sell_executed_order {
     ($self:expr, $order:expr, $candle:expr) => {{
+        $crate::handle_sell_executed_order!($self, $order, $candle, $order.qty - $order.filled_qty)
+    }};
+    ($self:expr, $order:expr, $candle:expr, $qty:expr) => {{
         let mut order = $order.clone();
+        let qty: f32 = $qty;
+
+        $self.balance += $crate::get_notional(order.price, qty);
+
+        let commission = order.commission;
+
+        match $self.commission_asset {
+            CommissionAsset::Token if $self.fee_balance >= commission => {
+                $self.fee_balance -= commission
+            }
+            // a short fee token balance falls back to the quote
+            _ => $self.balance -= commission,
+        }
 
-        $self.balance += order.price * order.qty;
-        $self.balance -= order.commission;
+        $self
+            .traded_notional
+            .push_back(($candle.get_start_time(), $crate::get_notional(order.price, qty)));
+
+        let borrowed = $crate::handle_borrow_reserved!($self, order, qty);
 
         $self.portfolio_frozen
                     .entry($candle.get_symbol())
-                    .and_modify(|v| *v -= order.qty);
+                    .and_modify(|v| *v -= qty - borrowed);
 
-        order.status = OrderStatus::Close;
-        order.finished_at = $candle.get_start_time();
+        // a short filled after the asset was bought back is covered by the holdings
+        $crate::handle_repay_borrowed!($self, order.symbol);
+
+        $self
+            .positions
+            .entry(order.symbol.clone())
+            .or_default()
+            .apply(&order.side, qty, order.price, order.commission);
+
+        $self.ledger.apply(&order, qty, $candle.get_start_time());
+
+        if let Some(bracket) = $self.brackets.get_mut(&order.id) {
+            bracket.filled_qty += qty;
+        }
+
+        order.last_qty = qty;
+        order.filled_qty += qty;
+
+        if order.filled_qty < order.qty {
+            order.status = OrderStatus::PartiallyFilled;
+        } else {
+            order.status = OrderStatus::Close;
+            order.finished_at = $candle.get_start_time();
+        }
 
         $self.activate.on_order($candle.get_start_time(), &order);
 
         debug!(
-            balance = $self.balance,
+            balance = %$self.balance,
             order = ?order,
             "sell order execution completed"
         );
@@ -28,19 +69,61 @@ macro_rules! handle_sell_executed_order {
 #[macro_export]
 macro_rules! handle_buy_executed_order {
     ($self:expr, $order:expr, $candle:expr) => {{
+        $crate::handle_buy_executed_order!($self, $order, $candle, $order.qty - $order.filled_qty)
+    }};
+    ($self:expr, $order:expr, $candle:expr, $qty:expr) => {{
         let mut order = $order.clone();
+        let qty: f32 = $qty;
+
+        let commission = order.commission;
+
+        let received = match $self.commission_asset {
+            CommissionAsset::Base => qty - $crate::from_amount(order.commission) / order.price,
+            CommissionAsset::Token if $self.fee_balance >= commission => {
+                $self.fee_balance -= commission;
+                qty
+            }
+            // a short fee token balance falls back to the quote
+            _ => {
+                $self.balance -= commission;
+                qty
+            }
+        };
 
         $self.portfolio_available
         .entry($candle.get_symbol())
-        .and_modify(|v| *v += order.qty)
-        .or_insert(order.qty);
+        .and_modify(|v| *v += received)
+        .or_insert(received);
 
-        $self.balance -= order.commission;
+        $crate::handle_repay_borrowed!($self, order.symbol);
 
-        debug!(balance = $self.balance,  order = ?order, "buy order execution completed");
+        $self
+            .positions
+            .entry(order.symbol.clone())
+            .or_default()
+            .apply(&order.side, received, order.price, order.commission);
 
-        order.status = OrderStatus::Close;
-        order.finished_at = $candle.get_start_time();
+        $self.ledger.apply(&order, received, $candle.get_start_time());
+
+        if let Some(bracket) = $self.brackets.get_mut(&order.id) {
+            bracket.filled_qty += received;
+        }
+
+        $self
+            .traded_notional
+            .push_back(($candle.get_start_time(), $crate::get_notional(order.price, qty)));
+
+        debug!(balance = %$self.balance,  order = ?order, "buy order execution completed");
+
+        order.last_qty = qty;
+        order.filled_qty += qty;
+
+        if order.filled_qty < order.qty {
+            order.status = OrderStatus::PartiallyFilled;
+        } else {
+            order.status = OrderStatus::Close;
+            order.finished_at = $candle.get_start_time();
+        }
 
         $self.activate.on_order($candle.get_start_time(), &order);
 
@@ -51,25 +134,23 @@ macro_rules! handle_buy_executed_order {
 #[macro_export]
 macro_rules! handle_cancel_order {
     ($self:expr, $order:expr, $candle:expr) => {{
+        $crate::handle_cancel_order!($self, $order, $candle, OrderStatus::Cancel)
+    }};
+    ($self:expr, $order:expr, $candle:expr, $status:expr) => {{
         let mut order = $order.clone();
+        let remaining = order.qty - order.filled_qty;
 
         match order.side {
             OrderSide::Buy => {
-                $self.balance += order.price * order.qty;
+                $self.balance += $crate::get_notional(order.price, remaining);
             }
             OrderSide::Sell => {
-                $self
-                    .portfolio_available
-                    .entry($candle.get_symbol())
-                    .and_modify(|v| *v += order.qty);
-
-                $self
-                    .portfolio_frozen
-                    .entry($candle.get_symbol())
-                    .and_modify(|v| *v -= order.qty);
+                $crate::handle_release_asset!($self, order, remaining);
+                $crate::handle_repay_borrowed!($self, order.symbol);
             }
         }
-        order.status = OrderStatus::Cancel;
+        order.last_qty = 0.0;
+        order.status = $status;
         order.finished_at = $candle.get_start_time();
 
         $self.activate.on_order($candle.get_start_time(), &order);
@@ -77,3 +158,94 @@ macro_rules! handle_cancel_order {
         order
     }};
 }
+
+#[macro_export]
+macro_rules! handle_repay_borrowed {
+    ($self:expr, $symbol:expr) => {{
+        if let Some(borrowed) = $self.portfolio_borrowed.get_mut(&$symbol) {
+            let available = $self
+                .portfolio_available
+                .entry($symbol.clone())
+                .or_insert(0.0);
+            let repay = borrowed.min(*available);
+
+            *borrowed -= repay;
+            *available -= repay;
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! handle_short_reserve_key {
+    ($self:expr, $order:expr) => {{
+        // the legs of a pair share the reserve of the stop-loss leg
+        Some($order.id)
+            .into_iter()
+            .chain($order.oco_id)
+            .find(|id| $self.short_reserves.contains_key(id))
+    }};
+}
+
+#[macro_export]
+macro_rules! handle_borrow_reserved {
+    ($self:expr, $order:expr, $qty:expr) => {{
+        let qty: f32 = $qty;
+        let mut borrowed = 0.0;
+
+        if let Some(key) = $crate::handle_short_reserve_key!($self, $order) {
+            if let Some(short) = $self.short_reserves.get_mut(&key) {
+                // the held part of the order is sold first
+                let held = $order.qty - $order.filled_qty - *short;
+
+                borrowed = (qty - held).clamp(0.0, *short);
+                *short -= borrowed;
+
+                if *short <= 0.0 {
+                    $self.short_reserves.remove(&key);
+                }
+            }
+        }
+
+        if borrowed > 0.0 {
+            $self
+                .portfolio_borrowed
+                .entry($order.symbol.clone())
+                .and_modify(|v| *v += borrowed)
+                .or_insert(borrowed);
+        }
+
+        borrowed
+    }};
+}
+
+#[macro_export]
+macro_rules! handle_release_asset {
+    ($self:expr, $order:expr, $qty:expr) => {{
+        let mut held: f32 = $qty;
+
+        if let Some(key) = $crate::handle_short_reserve_key!($self, $order) {
+            if let Some(short) = $self.short_reserves.get_mut(&key) {
+                // the part that was never borrowed is released first
+                let released = short.min(held);
+
+                *short -= released;
+                held -= released;
+
+                if *short <= 0.0 {
+                    $self.short_reserves.remove(&key);
+                }
+            }
+        }
+
+        $self
+            .portfolio_available
+            .entry($order.symbol.clone())
+            .and_modify(|v| *v += held)
+            .or_insert(held);
+
+        $self
+            .portfolio_frozen
+            .entry($order.symbol.clone())
+            .and_modify(|v| *v -= held);
+    }};
+}
@@ -0,0 +1,24 @@
+/// Margin account settings used when the agent is allowed to open short positions.
+///
+/// A sell exceeding the available asset balance reserves the margin for the missing
+/// part when it is placed, borrows it when it executes and repays it automatically
+/// as soon as the asset is bought back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarginConfig {
+    /// Interest charged on the borrowed value on every candle of the symbol
+    pub borrow_rate: f32,
+    /// Minimum equity to liabilities ratio required to open a new short
+    pub initial_margin: f32,
+    /// Equity to liabilities ratio below which the short is liquidated
+    pub maintenance_margin: f32,
+}
+
+impl Default for MarginConfig {
+    fn default() -> Self {
+        MarginConfig {
+            borrow_rate: 0.0,
+            initial_margin: 0.5,
+            maintenance_margin: 0.3,
+        }
+    }
+}
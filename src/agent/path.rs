@@ -0,0 +1,103 @@
+/// Order in which the prices of a candle are visited, used to resolve orders touched within the same candle
+pub trait IntrabarPath: Send {
+    /// Price points of the candle starting at the open and ending at the close.
+    /// `position` is the net quantity held in the candle symbol and `queued` the net
+    /// remaining quantity of its queued limit orders, both negative for selling.
+    fn get_path(
+        &self,
+        open: f32,
+        high: f32,
+        low: f32,
+        close: f32,
+        position: f32,
+        queued: f32,
+    ) -> [f32; 4];
+}
+
+/// Open, high, low, close
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OpenHighLowClose;
+
+impl IntrabarPath for OpenHighLowClose {
+    fn get_path(
+        &self,
+        open: f32,
+        high: f32,
+        low: f32,
+        close: f32,
+        _position: f32,
+        _queued: f32,
+    ) -> [f32; 4] {
+        [open, high, low, close]
+    }
+}
+
+/// Open, low, high, close
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OpenLowHighClose;
+
+impl IntrabarPath for OpenLowHighClose {
+    fn get_path(
+        &self,
+        open: f32,
+        high: f32,
+        low: f32,
+        close: f32,
+        _position: f32,
+        _queued: f32,
+    ) -> [f32; 4] {
+        [open, low, high, close]
+    }
+}
+
+/// Visit the extreme closest to the open first
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NearestExtremeFirst;
+
+impl IntrabarPath for NearestExtremeFirst {
+    fn get_path(
+        &self,
+        open: f32,
+        high: f32,
+        low: f32,
+        close: f32,
+        position: f32,
+        queued: f32,
+    ) -> [f32; 4] {
+        if high - open <= open - low {
+            OpenHighLowClose.get_path(open, high, low, close, position, queued)
+        } else {
+            OpenLowHighClose.get_path(open, high, low, close, position, queued)
+        }
+    }
+}
+
+/// Visit the extreme adverse to the held position first, so its stops fill before its targets.
+/// A flat agent visits the extreme adverse to its queued limit orders last, so an entry filled
+/// on the way is followed by the move against it. Without either it falls back to
+/// [`NearestExtremeFirst`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Pessimistic;
+
+impl IntrabarPath for Pessimistic {
+    fn get_path(
+        &self,
+        open: f32,
+        high: f32,
+        low: f32,
+        close: f32,
+        position: f32,
+        queued: f32,
+    ) -> [f32; 4] {
+        // queued entries meet the adverse move after they filled, the reverse of a position
+        let exposure = if position != 0.0 { position } else { -queued };
+
+        if exposure > 0.0 {
+            OpenLowHighClose.get_path(open, high, low, close, position, queued)
+        } else if exposure < 0.0 {
+            OpenHighLowClose.get_path(open, high, low, close, position, queued)
+        } else {
+            NearestExtremeFirst.get_path(open, high, low, close, position, queued)
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::order::OrderSide;
+use crate::{get_notional, Amount};
+
+/// Open quantity of a symbol with its entry price and the profit already taken
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Position {
+    /// Net quantity, negative for a short
+    pub qty: f32,
+    /// Average price the open quantity was entered at
+    pub entry_price: f32,
+    /// Entry value of the open quantity
+    pub cost_basis: Amount,
+    /// Profit of the closed quantity net of all commissions
+    pub realized_pnl: Amount,
+}
+
+impl Position {
+    /// Apply an execution of `qty` at `price`
+    pub fn apply(&mut self, side: &OrderSide, qty: f32, price: f32, commission: Amount) {
+        let qty = match side {
+            OrderSide::Buy => qty,
+            OrderSide::Sell => -qty,
+        };
+
+        if self.qty == 0.0 || self.qty.signum() == qty.signum() {
+            let total = self.qty.abs() + qty.abs();
+
+            if total > 0.0 {
+                self.entry_price = (self.qty.abs() * self.entry_price + qty.abs() * price) / total;
+            }
+        } else {
+            let closed = qty.abs().min(self.qty.abs());
+
+            self.realized_pnl += get_notional(price, closed * self.qty.signum())
+                - get_notional(self.entry_price, closed * self.qty.signum());
+
+            if qty.abs() > closed {
+                // the position flips, the rest is entered at the fill price
+                self.entry_price = price;
+            }
+        }
+
+        self.qty += qty;
+
+        if self.qty == 0.0 {
+            self.entry_price = 0.0;
+        }
+
+        self.cost_basis = get_notional(self.entry_price, self.qty.abs());
+        self.realized_pnl -= commission;
+    }
+
+    /// Profit of the open quantity marked at the price
+    pub fn get_unrealized_pnl(&self, price: f32) -> Amount {
+        get_notional(price, self.qty) - get_notional(self.entry_price, self.qty)
+    }
+}
@@ -0,0 +1,58 @@
+use crate::candle::CandleTrait;
+use crate::order::OrderSide;
+
+/// Price impact applied to market fills
+pub trait SlippageModel<C>: Send {
+    /// Adverse price move per unit for filling `qty` at `price` within the candle
+    fn get_slippage(&self, side: &OrderSide, price: f32, qty: f32, candle: &C) -> f32;
+}
+
+/// Fill exactly at the reference price
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoSlippage;
+
+impl<C> SlippageModel<C> for NoSlippage {
+    fn get_slippage(&self, _side: &OrderSide, _price: f32, _qty: f32, _candle: &C) -> f32 {
+        0.0
+    }
+}
+
+/// Fixed slippage in basis points of the price
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FixedSlippage {
+    pub bps: f32,
+}
+
+impl<C> SlippageModel<C> for FixedSlippage {
+    fn get_slippage(&self, _side: &OrderSide, price: f32, _qty: f32, _candle: &C) -> f32 {
+        price * self.bps / 10_000.0
+    }
+}
+
+/// Slippage as a fraction of the candle range (high - low)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RangeSlippage {
+    pub fraction: f32,
+}
+
+impl<C: CandleTrait> SlippageModel<C> for RangeSlippage {
+    fn get_slippage(&self, _side: &OrderSide, _price: f32, _qty: f32, candle: &C) -> f32 {
+        (candle.get_high() - candle.get_low()) * self.fraction
+    }
+}
+
+/// Slippage growing with the share of the candle volume taken by the order,
+/// `impact` is the relative price move for trading the whole candle volume
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VolumeSlippage {
+    pub impact: f32,
+}
+
+impl<C: CandleTrait> SlippageModel<C> for VolumeSlippage {
+    fn get_slippage(&self, _side: &OrderSide, price: f32, qty: f32, candle: &C) -> f32 {
+        match candle.get_volume() {
+            Some(volume) if volume > 0.0 => price * self.impact * (qty / volume),
+            _ => 0.0,
+        }
+    }
+}
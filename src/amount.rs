@@ -0,0 +1,45 @@
+#[cfg(feature = "decimal")]
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+
+/// Cash amount of the agent, an exact decimal with the `decimal` feature
+#[cfg(not(feature = "decimal"))]
+pub type Amount = f32;
+
+/// Cash amount of the agent, an exact decimal with the `decimal` feature
+#[cfg(feature = "decimal")]
+pub type Amount = rust_decimal::Decimal;
+
+/// Convert a price, quantity or rate, keeping the shortest decimal representation
+#[cfg(not(feature = "decimal"))]
+pub fn to_amount(value: f32) -> Amount {
+    value
+}
+
+/// Convert a price, quantity or rate, keeping the shortest decimal representation
+#[cfg(feature = "decimal")]
+pub fn to_amount(value: f32) -> Amount {
+    // the display form is the shortest one reading back as the same f32
+    value
+        .to_string()
+        .parse()
+        .ok()
+        .or_else(|| Amount::from_f32(value))
+        .unwrap_or_default()
+}
+
+/// Convert an amount back for price and quantity arithmetic
+#[cfg(not(feature = "decimal"))]
+pub fn from_amount(value: Amount) -> f32 {
+    value
+}
+
+/// Convert an amount back for price and quantity arithmetic
+#[cfg(feature = "decimal")]
+pub fn from_amount(value: Amount) -> f32 {
+    value.to_f32().unwrap_or_default()
+}
+
+/// Price times quantity, multiplied exactly with the `decimal` feature
+pub fn get_notional(price: f32, qty: f32) -> Amount {
+    to_amount(price) * to_amount(qty)
+}
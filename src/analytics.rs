@@ -0,0 +1,282 @@
+use crate::types::TimeStamp;
+use crate::{from_amount, EquityPoint, Trade};
+use std::time::Duration;
+
+/// A year of round-the-clock trading
+const YEAR: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Annualization of the per-round returns
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricsConfig {
+    /// Rounds in a year, 365 for daily candles
+    pub periods_per_year: f32,
+    /// Yearly return of the risk free asset
+    pub risk_free_rate: f32,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            periods_per_year: 365.0,
+            risk_free_rate: 0.0,
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// Annualize rounds lasting `interval`, trading the whole year
+    pub fn from_interval(interval: Duration) -> Self {
+        MetricsConfig {
+            periods_per_year: YEAR.as_secs_f32() / interval.as_secs_f32().max(f32::EPSILON),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_risk_free_rate(mut self, risk_free_rate: f32) -> Self {
+        self.risk_free_rate = risk_free_rate;
+        self
+    }
+}
+
+/// Risk and return of an equity curve, ratios are 0 when they are undefined
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Metrics {
+    /// Final equity relative to the initial equity minus one
+    pub total_return: f32,
+    /// Compound annual growth rate
+    pub cagr: f32,
+    /// Annualized standard deviation of the round returns
+    pub volatility: f32,
+    pub sharpe: f32,
+    pub sortino: f32,
+    /// Largest fall from a peak relative to the peak
+    pub max_drawdown: f32,
+    /// Longest time spent below a peak, in timestamp units
+    pub max_drawdown_duration: TimeStamp,
+    /// CAGR relative to the maximum drawdown
+    pub calmar: f32,
+    /// Share of the rounds ending with assets held or borrowed
+    pub exposure: f32,
+}
+
+impl Metrics {
+    /// Compute the metrics of the equity curve of an agent
+    pub fn from_equity_curve(curve: &[EquityPoint], config: &MetricsConfig) -> Self {
+        let equity = curve
+            .iter()
+            .map(|point| from_amount(point.get_equity()))
+            .collect::<Vec<_>>();
+
+        let (Some(first), Some(last)) = (equity.first(), equity.last()) else {
+            return Metrics::default();
+        };
+
+        let returns = equity
+            .windows(2)
+            .map(|w| if w[0] != 0.0 { w[1] / w[0] - 1.0 } else { 0.0 })
+            .collect::<Vec<_>>();
+
+        let total_return = if *first != 0.0 {
+            last / first - 1.0
+        } else {
+            0.0
+        };
+
+        let cagr = if returns.is_empty() || total_return <= -1.0 {
+            total_return.max(-1.0)
+        } else {
+            (1.0 + total_return).powf(config.periods_per_year / returns.len() as f32) - 1.0
+        };
+
+        let risk_free = config.risk_free_rate / config.periods_per_year;
+        let excess = returns.iter().map(|r| r - risk_free).collect::<Vec<_>>();
+        let mean = get_mean(&excess);
+        let annualization = config.periods_per_year.sqrt();
+
+        let deviation = get_deviation(&returns);
+        let downside_deviation = (excess.iter().map(|r| r.min(0.0).powi(2)).sum::<f32>()
+            / excess.len().max(1) as f32)
+            .sqrt();
+
+        let (max_drawdown, max_drawdown_duration) = get_max_drawdown(curve, &equity);
+
+        let exposed = curve
+            .iter()
+            .filter(|point| {
+                point.positions != Default::default() || point.frozen != Default::default()
+            })
+            .count();
+
+        Metrics {
+            total_return,
+            cagr,
+            volatility: deviation * annualization,
+            sharpe: get_ratio(mean, deviation) * annualization,
+            sortino: get_ratio(mean, downside_deviation) * annualization,
+            max_drawdown,
+            max_drawdown_duration,
+            calmar: get_ratio(cagr, max_drawdown),
+            exposure: exposed as f32 / curve.len() as f32,
+        }
+    }
+}
+
+/// Statistics of the closed round trip trades, ratios are 0 when they are undefined
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TradeStats {
+    pub trades: usize,
+    /// Share of the trades with a positive profit
+    pub win_rate: f32,
+    /// Gross profit relative to the gross loss
+    pub profit_factor: f32,
+    /// Average profit per trade
+    pub expectancy: f32,
+    pub average_win: f32,
+    /// Average loss of the losing trades, a negative value
+    pub average_loss: f32,
+    pub max_consecutive_losses: usize,
+}
+
+impl TradeStats {
+    /// Compute the statistics of the trades in the order they were closed
+    pub fn from_trades(trades: &[Trade]) -> Self {
+        let pnl = trades
+            .iter()
+            .map(|trade| from_amount(trade.pnl))
+            .collect::<Vec<_>>();
+
+        let wins = pnl.iter().copied().filter(|p| *p > 0.0).collect::<Vec<_>>();
+        let losses = pnl.iter().copied().filter(|p| *p < 0.0).collect::<Vec<_>>();
+
+        let gross_profit = wins.iter().sum::<f32>();
+        let gross_loss = -losses.iter().sum::<f32>();
+
+        let mut consecutive_losses = 0;
+        let mut max_consecutive_losses = 0;
+
+        for p in pnl.iter() {
+            if *p < 0.0 {
+                consecutive_losses += 1;
+                max_consecutive_losses = max_consecutive_losses.max(consecutive_losses);
+            } else {
+                consecutive_losses = 0;
+            }
+        }
+
+        TradeStats {
+            trades: trades.len(),
+            win_rate: get_ratio(wins.len() as f32, trades.len() as f32),
+            profit_factor: get_ratio(gross_profit, gross_loss),
+            expectancy: get_mean(&pnl),
+            average_win: get_mean(&wins),
+            average_loss: get_mean(&losses),
+            max_consecutive_losses,
+        }
+    }
+}
+
+fn get_mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+/// Sample standard deviation
+fn get_deviation(values: &[f32]) -> f32 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+
+    let mean = get_mean(values);
+    let variance =
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / (values.len() - 1) as f32;
+
+    variance.sqrt()
+}
+
+fn get_ratio(value: f32, risk: f32) -> f32 {
+    if risk > 0.0 {
+        value / risk
+    } else {
+        0.0
+    }
+}
+
+/// Largest relative fall from a peak and the longest time from a peak to its recovery
+fn get_max_drawdown(curve: &[EquityPoint], equity: &[f32]) -> (f32, TimeStamp) {
+    let mut peak = f32::MIN;
+    let mut peak_ts = 0;
+    let mut max_drawdown = 0.0f32;
+    let mut max_duration = 0;
+    let mut underwater = false;
+
+    for (point, value) in curve.iter().zip(equity) {
+        if underwater || *value < peak {
+            // a drawdown lasts until the peak is recovered, or until the end of the curve
+            max_duration = max_duration.max(point.ts - peak_ts);
+        }
+
+        if *value >= peak {
+            peak = *value;
+            peak_ts = point.ts;
+            underwater = false;
+        } else {
+            underwater = true;
+
+            if peak > 0.0 {
+                max_drawdown = max_drawdown.max(1.0 - value / peak);
+            }
+        }
+    }
+
+    (max_drawdown, max_duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, MetricsConfig};
+    use crate::{to_amount, EquityPoint};
+    use std::time::Duration;
+
+    fn get_curve(equity: &[f32]) -> Vec<EquityPoint> {
+        equity
+            .iter()
+            .enumerate()
+            .map(|(ts, value)| EquityPoint {
+                ts: ts as u64,
+                cash: to_amount(*value),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_metrics_drawdown() {
+        let curve = get_curve(&[100.0, 120.0, 90.0, 108.0, 130.0, 117.0]);
+        let metrics = Metrics::from_equity_curve(&curve, &MetricsConfig::default());
+
+        assert!((metrics.total_return - 0.17).abs() < 1e-5);
+        assert!((metrics.max_drawdown - 0.25).abs() < 1e-5);
+        assert_eq!(metrics.max_drawdown_duration, 3);
+        assert_eq!(metrics.exposure, 0.0);
+        assert!(metrics.sortino > metrics.sharpe);
+        assert!((metrics.calmar - metrics.cagr / 0.25).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_metrics_annualization() {
+        let curve = get_curve(&[100.0, 101.0, 102.01]);
+        let config = MetricsConfig::from_interval(Duration::from_secs(24 * 60 * 60));
+        assert_eq!(config.periods_per_year, 365.0);
+
+        let metrics = Metrics::from_equity_curve(&curve, &config);
+
+        // one percent a day compounded over a year
+        assert!((metrics.cagr / (1.01f32.powi(365) - 1.0) - 1.0).abs() < 1e-3);
+        assert!(metrics.volatility < 1e-4);
+        assert_eq!(metrics.max_drawdown, 0.0);
+        assert_eq!(metrics.calmar, 0.0);
+    }
+}
@@ -1,19 +1,32 @@
 use crate::activate::Activate;
 use crate::types::TimeStamp;
-use crate::{CalculateAgent, CandleTrait};
-use std::collections::HashMap;
+use crate::{
+    CalculateAgent, CalculateCommand, CandleSource, CandleTrait, EquityPoint, MapSource, Metrics,
+    MetricsConfig, OnEquity, Symbol,
+};
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
-use tracing::warn;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use tracing::debug;
 
-pub struct Calculate<'a, T, C>
+/// Rounds queued for every worker of the parallel mode
+const ROUND_BUFFER: usize = 64;
+
+pub struct Calculate<'a, T, C, S = MapSource<'a, C>>
 where
     T: Activate<C>,
     C: CandleTrait,
+    S: CandleSource<C>,
 {
-    candles: &'a HashMap<TimeStamp, Vec<C>>,
+    feed: Feed<C, S>,
     pointer: usize,
-    ts: Vec<TimeStamp>,
     agents: Vec<CalculateAgent<T, C>>,
+    equity_curves: Vec<Vec<EquityPoint>>,
+    on_equity: Option<OnEquity<'a>>,
 }
 
 impl<'a, T, C> Calculate<'a, T, C>
@@ -22,17 +35,37 @@ where
     C: CandleTrait + Debug,
 {
     pub fn new(candles: &'a HashMap<TimeStamp, Vec<C>>, agents: Vec<CalculateAgent<T, C>>) -> Self {
-        let mut ts = candles.keys().copied().collect::<Vec<_>>();
-        ts.sort();
+        Calculate::from_source(MapSource::new(candles), agents)
+    }
+}
 
+impl<'a, T, C, S> Calculate<'a, T, C, S>
+where
+    T: Activate<C>,
+    C: CandleTrait + Debug,
+    S: CandleSource<C>,
+{
+    /// Run the agents off candles read lazily from the source
+    pub fn from_source(source: S, agents: Vec<CalculateAgent<T, C>>) -> Self {
         Calculate {
-            candles,
+            feed: Feed {
+                source,
+                prev: None,
+                candle: PhantomData,
+            },
             pointer: 1,
+            equity_curves: agents.iter().map(|_| Vec::new()).collect(),
+            on_equity: None,
             agents,
-            ts,
         }
     }
 
+    /// Stream every recorded equity point with the index of its agent
+    pub fn with_on_equity(mut self, on_equity: OnEquity<'a>) -> Self {
+        self.on_equity = Some(on_equity);
+        self
+    }
+
     pub fn get_agents(&self) -> &Vec<CalculateAgent<T, C>> {
         &self.agents
     }
@@ -41,6 +74,141 @@ where
         self.pointer
     }
 
+    /// Equity of every agent per timestamp, in the order of the agents
+    pub fn get_equity_curves(&self) -> &Vec<Vec<EquityPoint>> {
+        &self.equity_curves
+    }
+
+    /// Risk and return metrics of every agent, in the order of the agents
+    pub fn get_metrics(&self, config: &MetricsConfig) -> Vec<Metrics> {
+        self.equity_curves
+            .iter()
+            .map(|curve| Metrics::from_equity_curve(curve, config))
+            .collect()
+    }
+
+    /// Run all remaining rounds with the agents split across `workers` threads,
+    /// the results are the same as stepping the iterator to the end and the
+    /// equity points are recorded as soon as every worker finished their round
+    pub fn run_parallel(&mut self, workers: usize)
+    where
+        T: Send,
+        C: Send + Sync,
+        S::Batch: Send + Sync,
+    {
+        let Some(first) = self.feed.next_round() else {
+            return;
+        };
+
+        self.record_initial_equity(&first);
+
+        let mut agents = mem::take(&mut self.agents);
+        let chunk_size = agents.len().div_ceil(workers.max(1)).max(1);
+
+        thread::scope(|scope| {
+            let (senders, receivers): (Vec<_>, Vec<_>) = agents
+                .chunks_mut(chunk_size)
+                .map(|agents| {
+                    let (sender, receiver) =
+                        mpsc::sync_channel::<Arc<Round<C, S::Batch>>>(ROUND_BUFFER);
+                    let (points_sender, points) = mpsc::channel::<Vec<EquityPoint>>();
+
+                    scope.spawn(move || {
+                        for round in receiver {
+                            let round_points = agents
+                                .iter_mut()
+                                .map(|agent| round.perform(agent))
+                                .collect();
+
+                            if points_sender.send(round_points).is_err() {
+                                break;
+                            }
+                        }
+                    });
+
+                    (sender, points)
+                })
+                .unzip();
+
+            let mut pending = vec![VecDeque::new(); receivers.len()];
+            let mut round = Some(first);
+            let mut sent = 0;
+
+            while let Some(current) = round {
+                let current = Arc::new(current);
+
+                for sender in senders.iter() {
+                    // a worker only stops early when it panicked, the scope reports it
+                    let _ = sender.send(current.clone());
+                }
+
+                sent += 1;
+
+                self.record_rounds(&receivers, &mut pending, chunk_size, false);
+
+                round = self.feed.next_round();
+            }
+
+            drop(senders);
+
+            self.record_rounds(&receivers, &mut pending, chunk_size, true);
+
+            self.pointer += sent;
+        });
+
+        self.agents = agents;
+    }
+
+    /// Record every round all the workers finished, in the same order as the
+    /// sequential rounds, `wait` blocks until the workers are done
+    fn record_rounds(
+        &mut self,
+        receivers: &[Receiver<Vec<EquityPoint>>],
+        pending: &mut [VecDeque<Vec<EquityPoint>>],
+        chunk_size: usize,
+        wait: bool,
+    ) {
+        for (receiver, pending) in receivers.iter().zip(pending.iter_mut()) {
+            if wait {
+                pending.extend(receiver.iter());
+            } else {
+                pending.extend(receiver.try_iter());
+            }
+        }
+
+        while !pending.is_empty() && pending.iter().all(|points| !points.is_empty()) {
+            for (chunk, points) in pending.iter_mut().enumerate() {
+                let Some(points) = points.pop_front() else {
+                    continue;
+                };
+
+                for (offset, point) in points.into_iter().enumerate() {
+                    self.record_equity(chunk * chunk_size + offset, point);
+                }
+            }
+        }
+    }
+
+    /// The curve starts with the initial cash before the first round
+    fn record_initial_equity(&mut self, round: &Round<C, S::Batch>) {
+        if self.pointer != 1 {
+            return;
+        }
+
+        for index in 0..self.agents.len() {
+            let point = self.agents[index].get_equity_point(round.prev_ts);
+            self.record_equity(index, point);
+        }
+    }
+
+    fn record_equity(&mut self, index: usize, point: EquityPoint) {
+        if let Some(on_equity) = self.on_equity.as_mut() {
+            on_equity(index, &point);
+        }
+
+        self.equity_curves[index].push(point);
+    }
+
     pub fn on_end(&mut self) {
         for agent in self.agents.iter_mut() {
             agent.on_end();
@@ -48,47 +216,354 @@ where
     }
 }
 
-impl<T, C> Iterator for Calculate<'_, T, C>
+/// Pairs every batch of the source with the one before it
+struct Feed<C, S: CandleSource<C>> {
+    source: S,
+    prev: Option<(TimeStamp, Arc<S::Batch>)>,
+    candle: PhantomData<C>,
+}
+
+impl<C, S> Feed<C, S>
 where
-    T: Activate<C>,
-    C: CandleTrait + Debug,
+    C: CandleTrait,
+    S: CandleSource<C>,
 {
-    type Item = ();
+    fn next_round(&mut self) -> Option<Round<C, S::Batch>> {
+        let prev = match self.prev.take() {
+            Some(prev) => prev,
+            None => {
+                let (ts, batch) = self.source.next_batch()?;
+                (ts, Arc::new(batch))
+            }
+        };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let prev_ts = self.ts.get(self.pointer - 1)?;
-        let prev_candles = self.candles.get(prev_ts)?;
+        let Some((ts, batch)) = self.source.next_batch() else {
+            self.prev = Some(prev);
+            return None;
+        };
+
+        let current = Arc::new(batch);
+        self.prev = Some((ts, current.clone()));
+
+        Some(Round::new(prev, (ts, current)))
+    }
+}
 
-        let ts = self.ts.get(self.pointer)?;
-        let current_candles = self.candles.get(ts)?;
+/// Candles of a single step shared by all agents
+struct Round<C, B> {
+    prev_ts: TimeStamp,
+    ts: TimeStamp,
+    prev_candles: Arc<B>,
+    current_candles: Arc<B>,
+    /// Index of the previous candle of every symbol
+    candle_map: HashMap<Symbol, usize>,
+    price_map: HashMap<Symbol, f32>,
+    candle: PhantomData<C>,
+}
 
+impl<C, B> Round<C, B>
+where
+    C: CandleTrait,
+    B: AsRef<[C]>,
+{
+    fn new(
+        (prev_ts, prev_candles): (TimeStamp, Arc<B>),
+        (ts, current_candles): (TimeStamp, Arc<B>),
+    ) -> Self {
         // Create a symbol-to-candle mapping for O(1) lookups
-        let candle_map: HashMap<_, _> = prev_candles.iter().map(|c| (c.get_symbol(), c)).collect();
+        let candle_map: HashMap<_, _> = prev_candles
+            .as_ref()
+            .as_ref()
+            .iter()
+            .enumerate()
+            .map(|(index, c)| (c.get_symbol(), index))
+            .collect();
         let price_map: HashMap<_, _> = current_candles
+            .as_ref()
+            .as_ref()
             .iter()
             .map(|c| (c.get_symbol(), c.get_open()))
             .collect();
 
-        for agent in self.agents.iter_mut() {
-            let orders = agent.activate(prev_candles, &price_map);
-            for order in orders {
-                let candle = candle_map.get(&order.get_symbol());
+        Round {
+            prev_ts,
+            ts,
+            prev_candles,
+            current_candles,
+            candle_map,
+            price_map,
+            candle: PhantomData,
+        }
+    }
+
+    /// Step the agent through the round, returns its equity at the end of the round
+    fn perform<T: Activate<C>>(&self, agent: &mut CalculateAgent<T, C>) -> EquityPoint
+    where
+        C: Debug,
+    {
+        let prev_candles = self.prev_candles.as_ref().as_ref();
+        let current_candles = self.current_candles.as_ref().as_ref();
+
+        let orders = agent.activate(prev_candles, &self.price_map);
+        for order in orders {
+            let candle = match order {
+                // a cancel across all symbols only needs the round timestamp
+                CalculateCommand::CancelAll { symbol: None, .. } => prev_candles.first(),
+                _ => self
+                    .candle_map
+                    .get(&order.get_symbol())
+                    .map(|index| &prev_candles[*index]),
+            };
 
-                if let Some(&candle) = candle {
-                    let result = agent.perform_order(order, candle);
+            if let Some(candle) = candle {
+                let result = agent.perform_order(order, candle);
 
-                    if let Err(e) = result {
-                        warn!(error = ?e, "Error performing order");
-                    }
+                if let Err(e) = result {
+                    debug!(error = ?e, "Error performing order");
                 }
             }
+        }
 
-            for candle in current_candles.iter() {
-                agent.perform_candle(candle);
-            }
+        for candle in current_candles.iter() {
+            agent.perform_candle(candle);
+        }
+
+        agent.on_end_round(self.ts, current_candles)
+    }
+}
+
+impl<T, C, S> Iterator for Calculate<'_, T, C, S>
+where
+    T: Activate<C>,
+    C: CandleTrait + Debug,
+    S: CandleSource<C>,
+{
+    type Item = ();
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let round = self.feed.next_round()?;
+
+        self.record_initial_equity(&round);
+
+        for index in 0..self.agents.len() {
+            let point = round.perform(&mut self.agents[index]);
+            self.record_equity(index, point);
         }
 
         self.pointer += 1;
         Some(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Calculate;
+    use crate::order::Order;
+    use crate::test_utils::{init_tracing, Candle};
+    use crate::types::TimeStamp;
+    use crate::{
+        buy_market, from_amount, Activate, CalculateAgent, CalculateCommand, CalculateResult,
+        EquityPoint, Symbol,
+    };
+    use std::collections::HashMap;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+
+    #[derive(Debug, Default)]
+    struct BuyOnceActivate {
+        rounds: Mutex<Vec<TimeStamp>>,
+    }
+
+    impl Activate<Candle> for &BuyOnceActivate {
+        fn activate(
+            &self,
+            candles: &[Candle],
+            _prices: &HashMap<Symbol, f32>,
+            results: &CalculateResult,
+            _active: &HashMap<Symbol, Vec<Order>>,
+        ) -> Vec<CalculateCommand> {
+            if results.executed_orders > 0 {
+                return vec![CalculateCommand::None];
+            }
+
+            candles
+                .iter()
+                .map(|candle| buy_market!(candle.symbol.clone(), 2.0))
+                .collect()
+        }
+
+        fn on_end_round(&mut self, ts: TimeStamp, _equity: &EquityPoint) {
+            self.rounds.lock().unwrap().push(ts);
+        }
+    }
+
+    fn get_candles() -> HashMap<TimeStamp, Vec<Candle>> {
+        let candle = |start_time, close| Candle {
+            symbol: "BTC".to_string(),
+            start_time,
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: None,
+        };
+
+        HashMap::from([
+            (1, vec![candle(1, 100.0)]),
+            (2, vec![candle(2, 110.0)]),
+            (3, vec![candle(3, 90.0)]),
+        ])
+    }
+
+    #[test]
+    fn test_calculate_equity_curve() {
+        init_tracing();
+
+        let candles = get_candles();
+        let activate = BuyOnceActivate::default();
+        let agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let mut streamed = vec![];
+        let mut calculate =
+            Calculate::new(&candles, vec![agent]).with_on_equity(Box::new(|index, point| {
+                streamed.push((index, point.ts, from_amount(point.get_equity())))
+            }));
+
+        for _ in calculate.by_ref() {}
+
+        let curve = calculate.get_equity_curves()[0]
+            .iter()
+            .map(|point| {
+                (
+                    point.ts,
+                    from_amount(point.cash),
+                    from_amount(point.positions),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            curve,
+            vec![(1, 1000.0, 0.0), (2, 800.0, 220.0), (3, 800.0, 180.0)]
+        );
+
+        drop(calculate);
+        assert_eq!(
+            streamed,
+            vec![(0, 1, 1000.0), (0, 2, 1020.0), (0, 3, 980.0)]
+        );
+    }
+
+    #[test]
+    fn test_calculate_end_round() {
+        init_tracing();
+
+        let candles = get_candles();
+        let activate = BuyOnceActivate::default();
+        let agent = CalculateAgent::new(1000.0, 0.0, Box::new(&activate));
+
+        let mut calculate = Calculate::new(&candles, vec![agent]);
+
+        for _ in calculate.by_ref() {}
+
+        let result = calculate.get_agents()[0].get_result();
+
+        assert_eq!(from_amount(result.min_equity), 980.0);
+        assert_eq!(from_amount(result.peak_equity), 1020.0);
+        assert_eq!(*activate.rounds.lock().unwrap(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_calculate_parallel() {
+        init_tracing();
+
+        let candles = get_candles();
+        let activate = BuyOnceActivate::default();
+
+        let get_agents = || {
+            (0..5)
+                .map(|i| {
+                    CalculateAgent::new(
+                        300.0 + 100.0 * i as f32,
+                        0.001 * i as f32,
+                        Box::new(&activate),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut sequential_streamed = vec![];
+        let mut sequential =
+            Calculate::new(&candles, get_agents()).with_on_equity(Box::new(|index, point| {
+                sequential_streamed.push((index, *point))
+            }));
+
+        for _ in sequential.by_ref() {}
+
+        let mut parallel_streamed = vec![];
+        let mut parallel =
+            Calculate::new(&candles, get_agents()).with_on_equity(Box::new(|index, point| {
+                parallel_streamed.push((index, *point))
+            }));
+
+        parallel.run_parallel(2);
+
+        assert_eq!(parallel.get_pointer(), sequential.get_pointer());
+        assert_eq!(parallel.get_equity_curves(), sequential.get_equity_curves());
+
+        for (a, b) in parallel.get_agents().iter().zip(sequential.get_agents()) {
+            let get_fills = |orders: &[Order]| {
+                orders
+                    .iter()
+                    .map(|o| (o.status.clone(), o.price, o.filled_qty, o.commission))
+                    .collect::<Vec<_>>()
+            };
+
+            assert_eq!(
+                get_fills(a.get_executed_orders()),
+                get_fills(b.get_executed_orders())
+            );
+            assert_eq!(a.get_result().balance, b.get_result().balance);
+        }
+
+        drop(sequential);
+        drop(parallel);
+        assert_eq!(parallel_streamed, sequential_streamed);
+    }
+
+    #[test]
+    fn test_calculate_source() {
+        init_tracing();
+
+        let candles = get_candles();
+        let activate = BuyOnceActivate::default();
+
+        let mut calculate = Calculate::new(
+            &candles,
+            vec![CalculateAgent::new(1000.0, 0.0, Box::new(&activate))],
+        );
+
+        for _ in calculate.by_ref() {}
+
+        let (sender, receiver) = mpsc::channel();
+
+        let mut batches = candles.clone().into_iter().collect::<Vec<_>>();
+        batches.sort_by_key(|(ts, _)| *ts);
+
+        thread::spawn(move || {
+            for batch in batches {
+                sender.send(batch).unwrap();
+            }
+        });
+
+        let mut streamed = Calculate::from_source(
+            receiver.into_iter(),
+            vec![CalculateAgent::new(1000.0, 0.0, Box::new(&activate))],
+        );
+
+        for _ in streamed.by_ref() {}
+
+        assert_eq!(streamed.get_pointer(), calculate.get_pointer());
+        assert_eq!(streamed.get_equity_curves(), calculate.get_equity_curves());
+    }
+}
@@ -7,4 +7,7 @@ pub trait CandleTrait {
     fn get_high(&self) -> f32;
     fn get_low(&self) -> f32;
     fn get_close(&self) -> f32;
+    fn get_volume(&self) -> Option<f32> {
+        None
+    }
 }
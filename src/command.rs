@@ -1,37 +1,134 @@
+use crate::order::{OrderSide, TimeInForce, TrailingDistance};
 use crate::types::{OrderId, Symbol, TimeStamp, UserId};
 
+/// Size of an order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stake {
+    /// Quantity of the base asset
+    Base(f32),
+    /// Notional in the quote asset, converted to a quantity with the fill price
+    Quote(f32),
+    /// Fraction of the agent equity, `0.1` is ten percent
+    Equity(f32),
+}
+
+impl From<f32> for Stake {
+    fn from(qty: f32) -> Self {
+        Stake::Base(qty)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum CalculateCommand {
     Unknown,
     None,
     BuyMarket {
         symbol: Symbol,
-        stake: f32,
+        stake: Stake,
         user_id: Option<UserId>,
     },
     SellMarket {
         symbol: Symbol,
-        stake: f32,
+        stake: Stake,
         user_id: Option<UserId>,
     },
     BuyLimit {
         symbol: Symbol,
-        stake: f32,
+        stake: Stake,
         price: f32,
         expiration: Option<TimeStamp>,
+        time_in_force: TimeInForce,
         user_id: Option<UserId>,
     },
     SellLimit {
         symbol: Symbol,
-        stake: f32,
+        stake: Stake,
+        price: f32,
+        expiration: Option<TimeStamp>,
+        time_in_force: TimeInForce,
+        user_id: Option<UserId>,
+    },
+    BuyStop {
+        symbol: Symbol,
+        stake: Stake,
+        stop_price: f32,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    },
+    SellStop {
+        symbol: Symbol,
+        stake: Stake,
+        stop_price: f32,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    },
+    BuyStopLimit {
+        symbol: Symbol,
+        stake: Stake,
+        stop_price: f32,
+        price: f32,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    },
+    SellStopLimit {
+        symbol: Symbol,
+        stake: Stake,
+        stop_price: f32,
         price: f32,
         expiration: Option<TimeStamp>,
         user_id: Option<UserId>,
     },
+    BuyBracket {
+        symbol: Symbol,
+        stake: Stake,
+        price: Option<f32>,
+        take_profit: f32,
+        stop_loss: f32,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    },
+    SellBracket {
+        symbol: Symbol,
+        stake: Stake,
+        price: Option<f32>,
+        take_profit: f32,
+        stop_loss: f32,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    },
+    BuyTrailingStop {
+        symbol: Symbol,
+        stake: Stake,
+        distance: TrailingDistance,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    },
+    SellTrailingStop {
+        symbol: Symbol,
+        stake: Stake,
+        distance: TrailingDistance,
+        expiration: Option<TimeStamp>,
+        user_id: Option<UserId>,
+    },
     CancelLimit {
         symbol: Symbol,
         id: OrderId,
     },
+    /// Cancel every queued order matching the filters, `None` matches any value
+    CancelAll {
+        symbol: Option<Symbol>,
+        user_id: Option<UserId>,
+        side: Option<OrderSide>,
+    },
+    /// Change the resting limit order in place, `None` keeps the current value
+    /// and an expiration of `Some(None)` clears it
+    AmendLimit {
+        symbol: Symbol,
+        id: OrderId,
+        price: Option<f32>,
+        stake: Option<Stake>,
+        expiration: Option<Option<TimeStamp>>,
+    },
 }
 
 impl CalculateCommand {
@@ -41,7 +138,20 @@ impl CalculateCommand {
             CalculateCommand::SellMarket { symbol, .. } => symbol.clone(),
             CalculateCommand::BuyLimit { symbol, .. } => symbol.clone(),
             CalculateCommand::SellLimit { symbol, .. } => symbol.clone(),
+            CalculateCommand::BuyStop { symbol, .. } => symbol.clone(),
+            CalculateCommand::SellStop { symbol, .. } => symbol.clone(),
+            CalculateCommand::BuyStopLimit { symbol, .. } => symbol.clone(),
+            CalculateCommand::SellStopLimit { symbol, .. } => symbol.clone(),
+            CalculateCommand::BuyBracket { symbol, .. } => symbol.clone(),
+            CalculateCommand::SellBracket { symbol, .. } => symbol.clone(),
+            CalculateCommand::BuyTrailingStop { symbol, .. } => symbol.clone(),
+            CalculateCommand::SellTrailingStop { symbol, .. } => symbol.clone(),
             CalculateCommand::CancelLimit { symbol, .. } => symbol.clone(),
+            CalculateCommand::AmendLimit { symbol, .. } => symbol.clone(),
+            CalculateCommand::CancelAll {
+                symbol: Some(symbol),
+                ..
+            } => symbol.clone(),
             _ => Symbol::default(),
         }
     }
@@ -52,7 +162,7 @@ macro_rules! buy_market {
     ($symbol:expr, $stake:expr) => {
         CalculateCommand::BuyMarket {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             user_id: None,
         }
     };
@@ -60,7 +170,7 @@ macro_rules! buy_market {
     ($symbol:expr, $stake:expr, user_id = $user_id:expr) => {
         CalculateCommand::BuyMarket {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             user_id: Some($user_id.to_owned()),
         }
     };
@@ -71,7 +181,7 @@ macro_rules! sell_market {
     ($symbol:expr, $stake:expr) => {
         CalculateCommand::SellMarket {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             user_id: None,
         }
     };
@@ -79,7 +189,7 @@ macro_rules! sell_market {
     ($symbol:expr, $stake:expr, user_id = $user_id:expr) => {
         CalculateCommand::SellMarket {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             user_id: Some($user_id.to_owned()),
         }
     };
@@ -90,9 +200,10 @@ macro_rules! buy_limit {
     ($symbol:expr, $stake:expr, $price:expr) => {
         CalculateCommand::BuyLimit {
             symbol: $symbol,
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: None,
+            time_in_force: Default::default(),
             user_id: None,
         }
     };
@@ -100,9 +211,21 @@ macro_rules! buy_limit {
     ($symbol:expr, $stake:expr, $price:expr, expiration = $expiration:expr) => {
         CalculateCommand::BuyLimit {
             symbol: $symbol,
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: Some($expiration),
+            time_in_force: Default::default(),
+            user_id: None,
+        }
+    };
+    // time in force
+    ($symbol:expr, $stake:expr, $price:expr, time_in_force = $time_in_force:expr) => {
+        CalculateCommand::BuyLimit {
+            symbol: $symbol,
+            stake: $stake.into(),
+            price: $price,
+            expiration: None,
+            time_in_force: $time_in_force,
             user_id: None,
         }
     };
@@ -110,9 +233,10 @@ macro_rules! buy_limit {
     ($symbol:expr, $stake:expr, $price:expr, user_id = $user_id:expr) => {
         CalculateCommand::BuyLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: None,
+            time_in_force: Default::default(),
             user_id: Some($user_id.to_owned()),
         }
     };
@@ -120,18 +244,20 @@ macro_rules! buy_limit {
     ($symbol:expr, $stake:expr, $price:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
         CalculateCommand::BuyLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: Some($expiration),
+            time_in_force: Default::default(),
             user_id: Some($user_id.to_owned()),
         }
     };
     ($symbol:expr, $stake:expr, $price:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
         CalculateCommand::BuyLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: Some($expiration),
+            time_in_force: Default::default(),
             user_id: Some($user_id.to_owned()),
         }
     };
@@ -142,9 +268,10 @@ macro_rules! sell_limit {
     ($symbol:expr, $stake:expr, $price:expr) => {
         CalculateCommand::SellLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: None,
+            time_in_force: Default::default(),
             user_id: None,
         }
     };
@@ -152,9 +279,21 @@ macro_rules! sell_limit {
     ($symbol:expr, $stake:expr, $price:expr, expiration = $expiration:expr) => {
         CalculateCommand::SellLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: Some($expiration),
+            time_in_force: Default::default(),
+            user_id: None,
+        }
+    };
+    // time in force
+    ($symbol:expr, $stake:expr, $price:expr, time_in_force = $time_in_force:expr) => {
+        CalculateCommand::SellLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: $price,
+            expiration: None,
+            time_in_force: $time_in_force,
             user_id: None,
         }
     };
@@ -162,9 +301,10 @@ macro_rules! sell_limit {
     ($symbol:expr, $stake:expr, $price:expr, user_id = $user_id:expr) => {
         CalculateCommand::SellLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: None,
+            time_in_force: Default::default(),
             user_id: Some($user_id.to_owned()),
         }
     };
@@ -172,16 +312,236 @@ macro_rules! sell_limit {
     ($symbol:expr, $stake:expr, $price:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
         CalculateCommand::SellLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
             price: $price,
             expiration: Some($expiration),
+            time_in_force: Default::default(),
             user_id: Some($user_id.to_owned()),
         }
     };
     ($symbol:expr, $stake:expr, $price:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
         CalculateCommand::SellLimit {
             symbol: $symbol.clone(),
-            stake: $stake,
+            stake: $stake.into(),
+            price: $price,
+            expiration: Some($expiration),
+            time_in_force: Default::default(),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! buy_stop {
+    ($symbol:expr, $stake:expr, $stop_price:expr) => {
+        CalculateCommand::BuyStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    // expiration
+    ($symbol:expr, $stake:expr, $stop_price:expr, expiration = $expiration:expr) => {
+        CalculateCommand::BuyStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    // user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // expiration + user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $stop_price:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
+        CalculateCommand::BuyStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! sell_stop {
+    ($symbol:expr, $stake:expr, $stop_price:expr) => {
+        CalculateCommand::SellStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    // expiration
+    ($symbol:expr, $stake:expr, $stop_price:expr, expiration = $expiration:expr) => {
+        CalculateCommand::SellStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    // user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // expiration + user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $stop_price:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
+        CalculateCommand::SellStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! buy_stop_limit {
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr) => {
+        CalculateCommand::BuyStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    // expiration
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, expiration = $expiration:expr) => {
+        CalculateCommand::BuyStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    // user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // expiration + user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
+        CalculateCommand::BuyStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! sell_stop_limit {
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr) => {
+        CalculateCommand::SellStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    // expiration
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, expiration = $expiration:expr) => {
+        CalculateCommand::SellStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    // user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // expiration + user_id
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
+            price: $price,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $stop_price:expr, $price:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
+        CalculateCommand::SellStopLimit {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            stop_price: $stop_price,
             price: $price,
             expiration: Some($expiration),
             user_id: Some($user_id.to_owned()),
@@ -189,6 +549,254 @@ macro_rules! sell_limit {
     };
 }
 
+#[macro_export]
+macro_rules! buy_bracket {
+    // market entry
+    ($symbol:expr, $stake:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr) => {
+        CalculateCommand::BuyBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: None,
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    ($symbol:expr, $stake:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: None,
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // limit entry
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr) => {
+        CalculateCommand::BuyBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, expiration = $expiration:expr) => {
+        CalculateCommand::BuyBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! sell_bracket {
+    // market entry
+    ($symbol:expr, $stake:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr) => {
+        CalculateCommand::SellBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: None,
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    ($symbol:expr, $stake:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: None,
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // limit entry
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr) => {
+        CalculateCommand::SellBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, expiration = $expiration:expr) => {
+        CalculateCommand::SellBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $price:expr, take_profit = $take_profit:expr, stop_loss = $stop_loss:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellBracket {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            price: Some($price),
+            take_profit: $take_profit,
+            stop_loss: $stop_loss,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! buy_trailing_stop {
+    ($symbol:expr, $stake:expr, $distance:expr) => {
+        CalculateCommand::BuyTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    // expiration
+    ($symbol:expr, $stake:expr, $distance:expr, expiration = $expiration:expr) => {
+        CalculateCommand::BuyTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    // user_id
+    ($symbol:expr, $stake:expr, $distance:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // expiration + user_id
+    ($symbol:expr, $stake:expr, $distance:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::BuyTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $distance:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
+        CalculateCommand::BuyTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! sell_trailing_stop {
+    ($symbol:expr, $stake:expr, $distance:expr) => {
+        CalculateCommand::SellTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: None,
+            user_id: None,
+        }
+    };
+    // expiration
+    ($symbol:expr, $stake:expr, $distance:expr, expiration = $expiration:expr) => {
+        CalculateCommand::SellTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: Some($expiration),
+            user_id: None,
+        }
+    };
+    // user_id
+    ($symbol:expr, $stake:expr, $distance:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: None,
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    // expiration + user_id
+    ($symbol:expr, $stake:expr, $distance:expr, expiration = $expiration:expr, user_id = $user_id:expr) => {
+        CalculateCommand::SellTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+    ($symbol:expr, $stake:expr, $distance:expr, user_id = $user_id:expr, expiration = $expiration:expr) => {
+        CalculateCommand::SellTrailingStop {
+            symbol: $symbol.clone(),
+            stake: $stake.into(),
+            distance: $distance,
+            expiration: Some($expiration),
+            user_id: Some($user_id.to_owned()),
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! cancel_limit {
     ($symbol:expr, $id:expr) => {
@@ -198,3 +806,76 @@ macro_rules! cancel_limit {
         }
     };
 }
+
+#[macro_export]
+macro_rules! cancel_all {
+    () => {
+        CalculateCommand::CancelAll {
+            symbol: None,
+            user_id: None,
+            side: None,
+        }
+    };
+    (side = $side:expr) => {
+        CalculateCommand::CancelAll {
+            symbol: None,
+            user_id: None,
+            side: Some($side),
+        }
+    };
+    (user_id = $user_id:expr) => {
+        CalculateCommand::CancelAll {
+            symbol: None,
+            user_id: Some($user_id.to_owned()),
+            side: None,
+        }
+    };
+    (user_id = $user_id:expr, side = $side:expr) => {
+        CalculateCommand::CancelAll {
+            symbol: None,
+            user_id: Some($user_id.to_owned()),
+            side: Some($side),
+        }
+    };
+    ($symbol:expr) => {
+        CalculateCommand::CancelAll {
+            symbol: Some($symbol.clone()),
+            user_id: None,
+            side: None,
+        }
+    };
+    ($symbol:expr, side = $side:expr) => {
+        CalculateCommand::CancelAll {
+            symbol: Some($symbol.clone()),
+            user_id: None,
+            side: Some($side),
+        }
+    };
+    ($symbol:expr, user_id = $user_id:expr) => {
+        CalculateCommand::CancelAll {
+            symbol: Some($symbol.clone()),
+            user_id: Some($user_id.to_owned()),
+            side: None,
+        }
+    };
+    ($symbol:expr, user_id = $user_id:expr, side = $side:expr) => {
+        CalculateCommand::CancelAll {
+            symbol: Some($symbol.clone()),
+            user_id: Some($user_id.to_owned()),
+            side: Some($side),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! amend_limit {
+    ($symbol:expr, $id:expr $(, price = $price:expr)? $(, stake = $stake:expr)? $(, expiration = $expiration:expr)?) => {
+        CalculateCommand::AmendLimit {
+            symbol: $symbol.clone(),
+            id: $id,
+            price: None $(.or(Some($price)))?,
+            stake: None $(.or(Some($stake.into())))?,
+            expiration: None $(.or(Some($expiration.into())))?,
+        }
+    };
+}
@@ -0,0 +1,26 @@
+use crate::types::TimeStamp;
+use crate::Amount;
+
+/// State of an agent at the end of a round, assets marked at the candle close
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct EquityPoint {
+    pub ts: TimeStamp,
+    /// Free cash
+    pub cash: Amount,
+    /// Cash held by the queued buy orders
+    pub reserved: Amount,
+    /// Available assets minus the borrowed assets
+    pub positions: Amount,
+    /// Assets held by the queued sell orders
+    pub frozen: Amount,
+}
+
+impl EquityPoint {
+    /// Total value of the agent
+    pub fn get_equity(&self) -> Amount {
+        self.cash + self.reserved + self.positions + self.frozen
+    }
+}
+
+/// Callback receiving the index of the agent and its equity point
+pub type OnEquity<'a> = Box<dyn FnMut(usize, &EquityPoint) + 'a>;
@@ -1,22 +1,49 @@
+//! Backtesting of trading agents over candle data.
+//!
+//! # Features
+//!
+//! - `decimal` keeps cash amounts in `rust_decimal::Decimal` instead of `f32`. The feature is
+//!   not additive: it changes the [`Amount`] type of public fields such as
+//!   [`CalculateResult::balance`], [`EquityPoint::cash`] and [`Order::commission`], so code
+//!   meant to build either way converts them with [`to_amount`] and [`from_amount`].
+
 pub use activate::Activate;
-pub use agent::CalculateAgent;
+pub use agent::{
+    CalculateAgent, CalculateAgentError, CommissionAsset, CommissionModel, CommissionTier,
+    FillModel, FixedSlippage, FlatCommission, FullFill, InstrumentSpec, IntrabarPath, Liquidity,
+    LotMatching, MakerTakerCommission, MarginConfig, NearestExtremeFirst, NoSlippage,
+    OpenHighLowClose, OpenLowHighClose, ParticipationFill, Pessimistic, Position, RangeSlippage,
+    SlippageModel, TieredCommission, TouchRule, Trade, VolumeSlippage,
+};
+pub use amount::{from_amount, get_notional, to_amount, Amount};
+pub use analytics::{Metrics, MetricsConfig, TradeStats};
 pub use calculate::Calculate;
 pub use candle::CandleTrait;
-pub use command::CalculateCommand;
-pub use order::{Order, OrderSide, OrderStatus, OrderType};
+pub use command::{CalculateCommand, Stake};
+pub use equity::{EquityPoint, OnEquity};
+pub use order::{
+    Order, OrderFilter, OrderReason, OrderSide, OrderStatus, OrderType, TimeInForce, Trailing,
+    TrailingDistance,
+};
 pub use result::CalculateResult;
+pub use source::{CandleSource, MapSource};
 pub use stats::CalculateStats;
 pub use types::OrderId;
 pub use types::Symbol;
 pub use types::TimeStamp;
+pub use types::UserId;
 
 mod activate;
 mod agent;
+mod amount;
+mod analytics;
 mod calculate;
 mod candle;
 mod command;
+mod equity;
 mod order;
 mod result;
+mod source;
 mod stats;
 #[cfg(test)]
 mod test_utils;
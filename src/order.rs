@@ -1,10 +1,18 @@
+use crate::agent::CalculateAgentError;
 use crate::types::{OrderId, Symbol, TimeStamp, UserId};
+use crate::Amount;
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub enum OrderStatus {
     Open,
+    /// Part of the quantity is executed, the rest is still resting
+    PartiallyFilled,
     Close,
     Cancel,
+    /// Removed from the queue by its time in force
+    Expired,
+    /// Refused at placement
+    Rejected,
 }
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
@@ -17,6 +25,69 @@ pub enum OrderSide {
 pub enum OrderType {
     Market,
     Limit,
+    StopMarket,
+    StopLimit,
+    TrailingStop,
+}
+
+/// Why an order was expired or rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderReason {
+    /// The relative expiration of the order passed
+    Expiration,
+    /// The rest of an immediate-or-cancel order was cancelled after its first candle
+    ImmediateOrCancel,
+    /// A fill-or-kill order could not fill in full within its first candle
+    FillOrKill,
+    /// The good-till-date timestamp of the order passed
+    GoodTillDate,
+    /// The agent refused to place the order
+    Rejected(CalculateAgentError),
+}
+
+/// How long a limit order stays in the queue
+#[derive(Debug, Default, Clone, Copy, Eq, Hash, PartialEq)]
+pub enum TimeInForce {
+    /// Rest until filled or cancelled
+    #[default]
+    GoodTillCancel,
+    /// Fill what the next candle allows and expire the rest
+    ImmediateOrCancel,
+    /// Fill the whole quantity within the next candle or expire
+    FillOrKill,
+    /// Rest until the timestamp
+    GoodTillDate(TimeStamp),
+    /// Rejected when the open of the first candle processing it would fill it right away
+    PostOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailingDistance {
+    Absolute(f32),
+    /// Fraction of the best price, `0.01` is one percent
+    Percent(f32),
+}
+
+impl TrailingDistance {
+    /// Stop price trailing the best price seen for the given order side
+    pub fn get_stop_price(&self, side: &OrderSide, best_price: f32) -> f32 {
+        let distance = match self {
+            TrailingDistance::Absolute(distance) => *distance,
+            TrailingDistance::Percent(percent) => best_price * percent,
+        };
+
+        match side {
+            OrderSide::Buy => best_price + distance,
+            OrderSide::Sell => best_price - distance,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trailing {
+    pub distance: TrailingDistance,
+    /// Highest price for a sell or lowest price for a buy since placement
+    pub best_price: f32,
 }
 
 #[derive(Debug, Clone)]
@@ -25,12 +96,74 @@ pub struct Order {
     pub created_at: TimeStamp,
     pub finished_at: TimeStamp,
     pub price: f32,
+    pub stop_price: Option<f32>,
     pub qty: f32,
-    pub commission: f32,
+    /// Quantity executed so far
+    pub filled_qty: f32,
+    /// Quantity executed by the latest execution
+    pub last_qty: f32,
+    pub commission: Amount,
+    /// Adverse price move per unit applied to the market fill
+    pub slippage: f32,
     pub id: OrderId,
     pub status: OrderStatus,
+    pub reason: Option<OrderReason>,
     pub side: OrderSide,
     pub order_type: OrderType,
     pub expiration: Option<TimeStamp>,
+    pub time_in_force: TimeInForce,
     pub user_id: Option<UserId>,
+    /// Entry order of a bracket this exit leg belongs to
+    pub parent_id: Option<OrderId>,
+    /// The other leg of a one-cancels-the-other pair
+    pub oco_id: Option<OrderId>,
+    pub trailing: Option<Trailing>,
+}
+
+/// Criteria for looking up orders, an unset criterion matches every order
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OrderFilter {
+    pub symbol: Option<Symbol>,
+    pub side: Option<OrderSide>,
+    pub status: Option<OrderStatus>,
+    pub user_id: Option<UserId>,
+    /// Creation time from the start, inclusive, to the end, exclusive
+    pub time_range: Option<(TimeStamp, TimeStamp)>,
+}
+
+impl OrderFilter {
+    pub fn with_symbol(mut self, symbol: Symbol) -> Self {
+        self.symbol = Some(symbol);
+        self
+    }
+
+    pub fn with_side(mut self, side: OrderSide) -> Self {
+        self.side = Some(side);
+        self
+    }
+
+    pub fn with_status(mut self, status: OrderStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn with_user_id(mut self, user_id: UserId) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    pub fn with_time_range(mut self, from: TimeStamp, to: TimeStamp) -> Self {
+        self.time_range = Some((from, to));
+        self
+    }
+
+    pub fn matches(&self, order: &Order) -> bool {
+        self.symbol.as_ref().is_none_or(|s| &order.symbol == s)
+            && self.side.as_ref().is_none_or(|s| &order.side == s)
+            && self.status.as_ref().is_none_or(|s| &order.status == s)
+            && (self.user_id.is_none() || order.user_id == self.user_id)
+            && self
+                .time_range
+                .is_none_or(|(from, to)| from <= order.created_at && order.created_at < to)
+    }
 }
@@ -1,12 +1,55 @@
-use crate::Symbol;
+use crate::{get_notional, Amount, EquityPoint, Position, Symbol};
 use std::collections::HashMap;
 
 #[derive(Debug, Default, Clone)]
 pub struct CalculateResult {
-    pub balance: f32,
-    pub min_balance: f32,
+    pub balance: Amount,
+    /// Lowest equity at the end of a round
+    pub min_equity: Amount,
+    /// Highest equity at the end of a round
+    pub peak_equity: Amount,
     pub opened_orders: usize,
+    /// Execution, cancel and expiry reports, one for every execution of a partially filled order
     pub executed_orders: usize,
     pub assets_available: HashMap<Symbol, f32>,
     pub assets_frozen: HashMap<Symbol, f32>,
+    pub assets_borrowed: HashMap<Symbol, f32>,
+    pub positions: HashMap<Symbol, Position>,
+    /// Cash held by the queued buy orders
+    pub reserved: Amount,
+    pub liabilities: Amount,
+    pub fee_balance: Amount,
+}
+
+impl CalculateResult {
+    /// Profit of the open positions marked at the prices
+    pub fn get_unrealized_pnl(&self, prices: &HashMap<Symbol, f32>) -> Amount {
+        self.positions
+            .iter()
+            .filter_map(|(symbol, position)| {
+                prices
+                    .get(symbol)
+                    .map(|price| position.get_unrealized_pnl(*price))
+            })
+            .sum()
+    }
+
+    /// Cash, reserved cash and the assets marked at the prices minus the borrowed assets
+    pub fn get_equity(&self, prices: &HashMap<Symbol, f32>) -> Amount {
+        let get_value = |assets: &HashMap<Symbol, f32>| {
+            assets
+                .iter()
+                .map(|(symbol, qty)| get_notional(*prices.get(symbol).unwrap_or(&0.0), *qty))
+                .sum::<Amount>()
+        };
+
+        EquityPoint {
+            cash: self.balance,
+            reserved: self.reserved,
+            positions: get_value(&self.assets_available) - get_value(&self.assets_borrowed),
+            frozen: get_value(&self.assets_frozen),
+            ..Default::default()
+        }
+        .get_equity()
+    }
 }
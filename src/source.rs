@@ -0,0 +1,53 @@
+use crate::types::TimeStamp;
+use std::collections::HashMap;
+
+/// Candles grouped by timestamp, read one batch at a time
+pub trait CandleSource<C> {
+    type Batch: AsRef<[C]>;
+
+    /// The next timestamp with its candles, timestamps have to increase
+    fn next_batch(&mut self) -> Option<(TimeStamp, Self::Batch)>;
+}
+
+/// Candles kept in memory, replayed in timestamp order
+pub struct MapSource<'a, C> {
+    candles: &'a HashMap<TimeStamp, Vec<C>>,
+    ts: Vec<TimeStamp>,
+    pointer: usize,
+}
+
+impl<'a, C> MapSource<'a, C> {
+    pub fn new(candles: &'a HashMap<TimeStamp, Vec<C>>) -> Self {
+        let mut ts = candles.keys().copied().collect::<Vec<_>>();
+        ts.sort();
+
+        MapSource {
+            candles,
+            ts,
+            pointer: 0,
+        }
+    }
+}
+
+impl<'a, C> CandleSource<C> for MapSource<'a, C> {
+    type Batch = &'a [C];
+
+    fn next_batch(&mut self) -> Option<(TimeStamp, Self::Batch)> {
+        let ts = *self.ts.get(self.pointer)?;
+        self.pointer += 1;
+
+        Some((ts, self.candles.get(&ts)?.as_slice()))
+    }
+}
+
+/// Any iterator of batches, such as a file reader or a channel receiver
+impl<C, I> CandleSource<C> for I
+where
+    I: Iterator<Item = (TimeStamp, Vec<C>)>,
+{
+    type Batch = Vec<C>;
+
+    fn next_batch(&mut self) -> Option<(TimeStamp, Self::Batch)> {
+        self.next()
+    }
+}
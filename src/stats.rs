@@ -1,13 +1,16 @@
 use crate::types::Symbol;
+use crate::{Amount, Position};
 use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct CalculateStats<'a> {
-    pub balance: f32,
+    pub balance: Amount,
     pub orders: usize,
     pub count: f32,
     pub expected: f32,
     pub real: f32,
     pub assets_available: &'a HashMap<Symbol, f32>,
     pub assets_frozen: &'a HashMap<Symbol, f32>,
+    pub assets_borrowed: &'a HashMap<Symbol, f32>,
+    pub positions: &'a HashMap<Symbol, Position>,
 }
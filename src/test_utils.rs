@@ -23,6 +23,7 @@ pub struct Candle {
     pub high: f32,
     pub low: f32,
     pub close: f32,
+    pub volume: Option<f32>,
 }
 
 impl CandleTrait for Candle {
@@ -49,12 +50,16 @@ impl CandleTrait for Candle {
     fn get_close(&self) -> f32 {
         self.close
     }
+
+    fn get_volume(&self) -> Option<f32> {
+        self.volume
+    }
 }
 
 #[macro_export]
 macro_rules! assert_agent_state {
     ($results:expr, $balance:expr, $opened_orders:expr, $executed_orders:expr, $orders:expr, $orders_len:expr) => {
-        assert_eq!($results.balance, $balance);
+        assert_eq!($results.balance, $crate::to_amount($balance));
         assert_eq!($results.opened_orders, $opened_orders);
         assert_eq!($results.executed_orders, $executed_orders);
 